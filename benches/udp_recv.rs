@@ -0,0 +1,103 @@
+//! Compares `Socks5Datagram::recv_from` and `recv_from_view` over loopback.
+//!
+//! `recv_from` receives the payload of a datagram with an IPv4 header straight
+//! into place, but has to move it for an IPv6 header, so the difference between
+//! the two runs is what the move costs. `recv_from_view` never moves it.
+//!
+//! Run with `cargo bench --bench udp_recv`.
+
+#![allow(clippy::unwrap_used)]
+
+use socks2::Socks5Datagram;
+use std::{
+    io::{Read, Write},
+    net::{Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+const ITERATIONS: u32 = 100_000;
+// Datagrams queued before timing their receives, small enough for the default
+// receive buffer.
+const BATCH: u32 = 8;
+const PAYLOADS: [usize; 2] = [1400, 16384];
+
+// A no-auth SOCKS5 server that answers UDP ASSOCIATE with `relay`.
+fn mock_proxy(relay: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        stream.write_all(&[5, 0]).unwrap();
+        let mut request = [0; 10];
+        stream.read_exact(&mut request).unwrap();
+
+        let SocketAddr::V4(relay) = relay else {
+            unreachable!()
+        };
+        let mut reply = vec![5, 0, 0, 1];
+        reply.extend(relay.ip().octets());
+        reply.extend(relay.port().to_be_bytes());
+        stream.write_all(&reply).unwrap();
+
+        let mut buf = [0; 64];
+        while matches!(stream.read(&mut buf), Ok(n) if n > 0) {}
+    });
+    addr
+}
+
+fn run<F>(name: &str, relay: &UdpSocket, local: SocketAddr, packet: &[u8], mut recv: F)
+where
+    F: FnMut(),
+{
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ITERATIONS / BATCH {
+        for _ in 0..BATCH {
+            relay.send_to(packet, local).unwrap();
+        }
+        let start = Instant::now();
+        for _ in 0..BATCH {
+            recv();
+        }
+        elapsed += start.elapsed();
+    }
+    let per_op = elapsed / ITERATIONS;
+    println!("{name:>27}: {per_op:?}/op");
+}
+
+fn main() {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let proxy = mock_proxy(relay.local_addr().unwrap());
+    let socks = Socks5Datagram::bind(proxy, "127.0.0.1:0", None).unwrap();
+    socks
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let local = socks.get_ref().local_addr().unwrap();
+
+    for size in PAYLOADS {
+        let payload: Vec<u8> = (0..size).map(|i| i.to_le_bytes()[0]).collect();
+        let mut ipv4 = vec![0, 0, 0, 1, 127, 0, 0, 1, 0, 53];
+        ipv4.extend(&payload);
+        let mut ipv6 = vec![0, 0, 0, 4];
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend([0, 53]);
+        ipv6.extend(&payload);
+
+        let mut buf = vec![0; size + 512];
+        for (header, packet) in [("ipv4", &ipv4), ("ipv6", &ipv6)] {
+            let name = format!("recv_from {header} {size}");
+            run(&name, &relay, local, packet, || {
+                let len = socks.recv_from(&mut buf).unwrap().0;
+                assert_eq!(len, size);
+            });
+            let name = format!("recv_from_view {header} {size}");
+            run(&name, &relay, local, packet, || {
+                let len = socks.recv_from_view(&mut buf).unwrap().1.len();
+                assert_eq!(len, size);
+            });
+        }
+    }
+}
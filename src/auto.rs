@@ -0,0 +1,362 @@
+//! Connects through a proxy whose SOCKS version is not known up front.
+
+use crate::{
+    unwrap_io_to_socks2_error, ConnectOptions, Error, Socks4Stream, Socks5Stream, TargetAddr,
+    ToResolvedAddrs, ToTargetAddr,
+};
+use std::{
+    collections::HashMap,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// A SOCKS protocol version spoken by a proxy.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ProxyProtocol {
+    /// SOCKS4, with SOCKS4A for domain targets.
+    Socks4,
+    /// SOCKS5.
+    Socks5,
+}
+
+/// Connects through proxies that speak either SOCKS5 or SOCKS4.
+///
+/// The first connection to a proxy sends a SOCKS5 greeting and falls back to
+/// SOCKS4 if the proxy answers it with another version or closes the
+/// connection. Once the proxy selected a SOCKS5 method, failures are returned
+/// as they are. The detected protocol is remembered per proxy address, so later
+/// connections skip the probe.
+///
+/// # Notes
+/// A SOCKS4-only proxy has to answer or close the connection on the SOCKS5
+/// greeting, a proxy that waits for more bytes blocks the detection.
+#[derive(Debug, Default)]
+pub struct AutoConnector {
+    userid: String,
+    credentials: Option<(String, String)>,
+    protocols: Mutex<HashMap<SocketAddr, ProxyProtocol>>,
+}
+
+impl AutoConnector {
+    /// Creates a connector with an empty SOCKS4 user id and no SOCKS5
+    /// authentication.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the user id sent to SOCKS4 proxies.
+    #[must_use]
+    pub fn userid(mut self, userid: &str) -> Self {
+        userid.clone_into(&mut self.userid);
+        self
+    }
+
+    /// Authenticates to SOCKS5 proxies with a username and password.
+    #[must_use]
+    pub fn password(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Connects to a target server through `proxy`, detecting its protocol if
+    /// it is not known yet.
+    ///
+    /// # Notes
+    /// See `Socks5Stream::connect_with_options()` and
+    /// `Socks4Stream::connect_with_options()`. A cached protocol that no longer
+    /// matches is detected again.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn connect<T, U>(
+        &self,
+        proxy: T,
+        target: &U,
+        options: &ConnectOptions,
+    ) -> io::Result<AutoStream>
+    where
+        T: ToResolvedAddrs,
+        U: ToTargetAddr,
+    {
+        // Resolved once, in case we have to connect again.
+        let proxy: Vec<SocketAddr> =
+            proxy.to_resolved_addrs(options.resolve_settings().resolver())?;
+        let target = target.to_target_addr()?;
+
+        let cached = proxy.iter().find_map(|addr| self.protocol(addr));
+        if cached == Some(ProxyProtocol::Socks4) {
+            match self.connect_socks4(&proxy, &target, options) {
+                Err(err) if is_mismatch(&err) => self.forget(&proxy),
+                result => return result,
+            }
+        }
+
+        if let Some(stream) = self.connect_socks5(&proxy, &target, options)? {
+            return Ok(stream);
+        }
+        self.forget(&proxy);
+        self.connect_socks4(&proxy, &target, options)
+    }
+
+    /// Returns the protocol detected for `proxy`, if any.
+    #[must_use]
+    pub fn protocol(&self, proxy: &SocketAddr) -> Option<ProxyProtocol> {
+        self.lock().get(proxy).copied()
+    }
+
+    /// Forgets all detected protocols.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, ProxyProtocol>> {
+        self.protocols
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn forget(&self, proxy: &[SocketAddr]) {
+        let mut protocols = self.lock();
+        for addr in proxy {
+            protocols.remove(addr);
+        }
+    }
+
+    // Remembers the protocol of the proxy address the stream is connected to.
+    fn remember(&self, socket: &TcpStream, protocol: ProxyProtocol) -> io::Result<()> {
+        let addr = socket.peer_addr()?;
+        self.lock().insert(addr, protocol);
+        Ok(())
+    }
+
+    fn connect_socks4(
+        &self,
+        proxy: &[SocketAddr],
+        target: &TargetAddr,
+        options: &ConnectOptions,
+    ) -> io::Result<AutoStream> {
+        let stream = Socks4Stream::connect_with_options(proxy, target, &self.userid, options)?;
+        self.remember(stream.get_ref(), ProxyProtocol::Socks4)?;
+        Ok(AutoStream::Socks4(stream))
+    }
+
+    // Returns `None` if the proxy did not answer the SOCKS5 greeting.
+    fn connect_socks5(
+        &self,
+        proxy: &[SocketAddr],
+        target: &TargetAddr,
+        options: &ConnectOptions,
+    ) -> io::Result<Option<AutoStream>> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        let Some(stream) = Socks5Stream::connect_if_socks5(proxy, target, credentials, options)?
+        else {
+            return Ok(None);
+        };
+        self.remember(stream.get_ref(), ProxyProtocol::Socks5)?;
+        Ok(Some(AutoStream::Socks5(stream)))
+    }
+}
+
+// Whether a SOCKS4 request was not understood, the proxy may speak SOCKS5 now.
+fn is_mismatch(err: &io::Error) -> bool {
+    matches!(
+        unwrap_io_to_socks2_error(err),
+        Some(Error::InvalidResponseVersion { .. })
+    ) || matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// A connection made by `AutoConnector`.
+#[derive(Debug)]
+pub enum AutoStream {
+    /// Connected through a SOCKS4 proxy.
+    Socks4(Socks4Stream),
+    /// Connected through a SOCKS5 proxy.
+    Socks5(Socks5Stream),
+}
+
+impl AutoStream {
+    /// Returns the protocol spoken with the proxy.
+    #[must_use]
+    pub const fn protocol(&self) -> ProxyProtocol {
+        match self {
+            Self::Socks4(_) => ProxyProtocol::Socks4,
+            Self::Socks5(_) => ProxyProtocol::Socks5,
+        }
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    #[must_use]
+    pub const fn get_ref(&self) -> &TcpStream {
+        match self {
+            Self::Socks4(stream) => stream.get_ref(),
+            Self::Socks5(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        match self {
+            Self::Socks4(stream) => stream.get_mut(),
+            Self::Socks5(stream) => stream.get_mut(),
+        }
+    }
+
+    /// Consumes the `AutoStream`, returning the inner `TcpStream`.
+    #[must_use]
+    pub fn into_inner(self) -> TcpStream {
+        match self {
+            Self::Socks4(stream) => stream.into_inner(),
+            Self::Socks5(stream) => stream.into_inner(),
+        }
+    }
+}
+
+impl Read for AutoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_mut().read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.get_mut().read_vectored(bufs)
+    }
+}
+
+impl Write for AutoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.get_mut().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl From<AutoStream> for TcpStream {
+    fn from(stream: AutoStream) -> Self {
+        stream.into_inner()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::{ext_bytes::BytesExt, mock};
+    use std::sync::mpsc;
+
+    #[test]
+    fn detect() {
+        let (tx, rx) = mpsc::channel();
+        let socks4 = tx.clone();
+        // a SOCKS4-only proxy that answers a SOCKS5 greeting with its version
+        let (proxy4, _) = mock::spawn(3, move |mut stream| {
+            let version = stream.read_be_u8().unwrap();
+            socks4.send(version).unwrap();
+            if version == 5 {
+                stream.write_all(&[0, 91]).unwrap();
+                return;
+            }
+            let mut request = [0; 8];
+            stream.read_exact(&mut request[1..]).unwrap();
+            while stream.read_be_u8().unwrap() != 0 {}
+            stream.write_all(&[0, 90, 0, 80, 192, 0, 2, 1]).unwrap();
+        });
+        let (proxy5, _) = mock::spawn(2, move |mut stream| {
+            tx.send(5).unwrap();
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "192.0.2.1:80".parse().unwrap());
+        });
+
+        let connector = AutoConnector::new();
+        let options = ConnectOptions::new();
+        for _ in 0..2 {
+            let stream = connector
+                .connect(proxy4, &"192.0.2.1:80", &options)
+                .unwrap();
+            assert_eq!(stream.protocol(), ProxyProtocol::Socks4);
+        }
+        // the second connection skipped the SOCKS5 greeting
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [5, 4, 4]);
+        assert_eq!(connector.protocol(&proxy4), Some(ProxyProtocol::Socks4));
+
+        for _ in 0..2 {
+            let stream = connector
+                .connect(proxy5, &"192.0.2.1:80", &options)
+                .unwrap();
+            assert_eq!(stream.protocol(), ProxyProtocol::Socks5);
+        }
+        assert_eq!(connector.protocol(&proxy5), Some(ProxyProtocol::Socks5));
+
+        connector.clear();
+        assert_eq!(connector.protocol(&proxy4), None);
+    }
+
+    #[test]
+    fn detect_reset() {
+        // a SOCKS4-only proxy that drops the connection on a SOCKS5 greeting
+        let (proxy, _) = mock::spawn(2, |mut stream| {
+            if stream.read_be_u8().unwrap() == 5 {
+                return;
+            }
+            let mut request = [0; 8];
+            stream.read_exact(&mut request[1..]).unwrap();
+            while stream.read_be_u8().unwrap() != 0 {}
+            stream.write_all(&[0, 90, 0, 80, 192, 0, 2, 1]).unwrap();
+        });
+
+        let connector = AutoConnector::new();
+        let stream = connector
+            .connect(proxy, &"192.0.2.1:80", &ConnectOptions::new())
+            .unwrap();
+        assert_eq!(stream.protocol(), ProxyProtocol::Socks4);
+    }
+
+    #[test]
+    fn socks5_failure() {
+        let (tx, rx) = mpsc::channel();
+        // drops the second CONNECT after the greeting succeeded
+        let mut conns = 0;
+        let (proxy, _) = mock::spawn(3, move |mut stream| {
+            conns += 1;
+            let version = stream.read_be_u8().unwrap();
+            tx.send(version).unwrap();
+            if version != 5 {
+                return;
+            }
+            let mut methods = vec![0; usize::from(stream.read_be_u8().unwrap())];
+            stream.read_exact(&mut methods).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            mock::read_request(&mut stream);
+            if conns == 1 {
+                mock::reply(&mut stream, 0, "192.0.2.1:80".parse().unwrap());
+            }
+        });
+
+        let connector = AutoConnector::new();
+        let options = ConnectOptions::new();
+        connector.connect(proxy, &"192.0.2.1:80", &options).unwrap();
+        let err = connector
+            .connect(proxy, &"192.0.2.1:80", &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // no SOCKS4 request was sent and the proxy is still known as SOCKS5
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [5, 5]);
+        assert_eq!(connector.protocol(&proxy), Some(ProxyProtocol::Socks5));
+    }
+}
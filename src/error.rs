@@ -1,5 +1,9 @@
+use crate::TargetAddr;
 use alloc::string::FromUtf8Error;
-use std::{io, net::SocketAddrV6};
+use std::{
+    io,
+    net::{SocketAddr, SocketAddrV6},
+};
 
 /// Errors from socks2
 ///
@@ -74,6 +78,37 @@ pub enum Error {
     /// UDP Bind Client has a limit of 4 GiB for buffers.
     /// Only occurs when using `Socks5Datagram` on windows.
     WinUDP4GiBLimit { size: usize },
+    /// Datagram would need more than 127 fragments.
+    TooManyFragments { count: usize },
+    /// Datagram is too large to be framed for UDP over TCP.
+    DatagramTooLarge { size: usize },
+    /// Operation cannot be done when tunneling UDP over TCP.
+    UnsupportedOverTcp { operation: &'static str },
+    /// Datagram has no default destination, see `Socks5Datagram::connect`.
+    DatagramNotConnected {},
+    /// Two options were set that cannot be used together.
+    IncompatibleOptions {
+        first: &'static str,
+        second: &'static str,
+    },
+    /// `MuxPeer` expired or its `UdpMux` stopped.
+    MuxPeerClosed {},
+    /// Control connection of a UDP association was closed, ending the association.
+    ControlConnectionLost {},
+    /// Peeked datagram came from another source than the relay.
+    DatagramNotFromRelay { addr: SocketAddr },
+    /// BIND listener already accepted its connection.
+    ListenerAccepted {},
+    /// Pending BIND request was aborted, see `AbortHandle`.
+    BindAborted {},
+    /// Expected peer could not be parsed as an IP, CIDR, or domain.
+    InvalidExpectedPeer { peer: String },
+    /// Host that connected to the BIND listener is not the expected peer.
+    UnexpectedBindPeer { peer: TargetAddr },
+    /// FTP `PORT` does not support IPv6, use `EPRT`.
+    FtpPortNoIPv6 { addr: SocketAddrV6 },
+    /// Domain would be resolved locally, but DNS leak protection is enabled.
+    LocalDnsRefused { domain: String },
 }
 
 /// Takes an `std::io::Error` and attempts to unwrap it into a `socks2::Error`.
@@ -137,7 +172,21 @@ impl PartialEq for Error {
             FailedPasswordAuth,
             InvalidReservedBytes,
             InvalidFragmentID,
-            WinUDP4GiBLimit
+            WinUDP4GiBLimit,
+            TooManyFragments,
+            DatagramTooLarge,
+            UnsupportedOverTcp,
+            DatagramNotConnected,
+            IncompatibleOptions,
+            MuxPeerClosed,
+            ControlConnectionLost,
+            DatagramNotFromRelay,
+            ListenerAccepted,
+            BindAborted,
+            InvalidExpectedPeer,
+            UnexpectedBindPeer,
+            FtpPortNoIPv6,
+            LocalDnsRefused
         )
     }
 }
@@ -179,7 +228,21 @@ impl From<Error> for io::Error {
             (FailedPasswordAuth, PermissionDenied),
             (InvalidReservedBytes, InvalidData),
             (InvalidFragmentID, InvalidData),
-            (WinUDP4GiBLimit, InvalidInput)
+            (WinUDP4GiBLimit, InvalidInput),
+            (TooManyFragments, InvalidInput),
+            (DatagramTooLarge, InvalidInput),
+            (UnsupportedOverTcp, Unsupported),
+            (DatagramNotConnected, NotConnected),
+            (IncompatibleOptions, InvalidInput),
+            (MuxPeerClosed, ConnectionAborted),
+            (ControlConnectionLost, ConnectionAborted),
+            (DatagramNotFromRelay, InvalidData),
+            (ListenerAccepted, InvalidInput),
+            (BindAborted, ConnectionAborted),
+            (InvalidExpectedPeer, InvalidInput),
+            (UnexpectedBindPeer, PermissionDenied),
+            (FtpPortNoIPv6, InvalidInput),
+            (LocalDnsRefused, PermissionDenied)
         )
     }
 }
@@ -218,6 +281,20 @@ impl core::fmt::Display for Error {
             Self::InvalidReservedBytes { bytes } => write!(f, "invalid reserved bytes '{bytes}'"),
             Self::InvalidFragmentID {fid} => write!(f, "invalid fragment ID '{fid}'"),
             Self::WinUDP4GiBLimit {size} => write!(f, "tried to write '{size}' bytes to UDPSocket, but writev/readv has a 4 GiB limit on windows"),
+            Self::TooManyFragments {count} => write!(f, "datagram needs '{count}' fragments, but at most 127 are allowed"),
+            Self::DatagramTooLarge {size} => write!(f, "datagram of '{size}' bytes is too large to send over TCP"),
+            Self::UnsupportedOverTcp {operation} => write!(f, "'{operation}' is not supported when tunneling UDP over TCP"),
+            Self::DatagramNotConnected {} => write!(f, "datagram is not connected to a target"),
+            Self::IncompatibleOptions { first, second } => write!(f, "options '{first}' and '{second}' cannot be used together"),
+            Self::MuxPeerClosed {} => write!(f, "peer expired or the multiplexer stopped"),
+            Self::ControlConnectionLost {} => write!(f, "control connection of the UDP association was lost"),
+            Self::DatagramNotFromRelay { addr } => write!(f, "datagram from '{addr}' did not come from the relay"),
+            Self::ListenerAccepted {} => write!(f, "listener already accepted a connection"),
+            Self::BindAborted {} => write!(f, "bind request was aborted"),
+            Self::InvalidExpectedPeer { peer } => write!(f, "invalid expected peer '{peer}'"),
+            Self::UnexpectedBindPeer { peer } => write!(f, "unexpected peer '{peer}' connected to the bind listener"),
+            Self::FtpPortNoIPv6 { addr } => write!(f, "FTP PORT does not support IPv6 '{addr}'"),
+            Self::LocalDnsRefused { domain } => write!(f, "refused to resolve '{domain}' locally with DNS leak protection enabled"),
         }
     }
 }
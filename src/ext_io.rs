@@ -1,17 +1,199 @@
-use std::{io, net::UdpSocket};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
 
 const VEC_SIZE: usize = 2;
+/// Reads take a third buffer, so a header can go in front of the caller's
+/// buffer and the payload that no longer fits after it.
+const RECV_VEC_SIZE: usize = 3;
 
 pub trait IOVecExt {
-    fn writev(&self, bufs: [&[u8]; 2]) -> io::Result<usize>;
-    fn readv(&self, bufs: [&mut [u8]; 2]) -> io::Result<usize>;
+    fn writev(&self, bufs: [&[u8]; VEC_SIZE]) -> io::Result<usize>;
+    fn readv(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<usize>;
+    /// Like `readv`, but the source of the datagram is returned.
+    fn readv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)>;
+    /// Like `readv_from`, but the datagram is left in the queue.
+    fn peekv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)>;
+}
+
+/// Batched `writev`/`readv`, one datagram per message.
+#[cfg(target_os = "linux")]
+pub trait MMsgExt {
+    /// Returns the number of messages sent.
+    fn sendmmsgv(&self, msgs: &[[&[u8]; VEC_SIZE]]) -> io::Result<usize>;
+    /// Blocks for the first message only, the length of each received message
+    /// is written to `lens`. Returns the number of messages received.
+    fn recvmmsgv<'a, I>(
+        &self,
+        msgs: I,
+        lens: &mut [usize],
+        buffers: &mut MMsgBuffers,
+    ) -> io::Result<usize>
+    where
+        I: IntoIterator<Item = [&'a mut [u8]; RECV_VEC_SIZE]>;
+}
+
+/// The message headers of `recvmmsgv`, kept so that their allocations can be
+/// reused from one call to the next.
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+pub struct MMsgBuffers {
+    iovecs: Vec<[libc::iovec; RECV_VEC_SIZE]>,
+    hdrs: Vec<libc::mmsghdr>,
+}
+
+// SAFETY: The pointers are set at the start of every call and not used after it.
+#[cfg(target_os = "linux")]
+#[allow(clippy::non_send_fields_in_send_ty)]
+unsafe impl Send for MMsgBuffers {}
+
+#[cfg(target_os = "linux")]
+impl core::fmt::Debug for MMsgBuffers {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MMsgBuffers")
+            .field("capacity", &self.hdrs.capacity())
+            .finish_non_exhaustive()
+    }
 }
 
 #[cfg(unix)]
-mod imp {
-    use super::{io, IOVecExt, UdpSocket, VEC_SIZE};
+fn recv_iovecs(bufs: &mut [&mut [u8]; RECV_VEC_SIZE]) -> [libc::iovec; RECV_VEC_SIZE] {
+    let [a, b, c] = bufs;
+    [a, b, c].map(|buf| libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use super::{io, recv_iovecs, MMsgBuffers, MMsgExt, UdpSocket, RECV_VEC_SIZE, VEC_SIZE};
+    use core::{cmp, mem, ptr};
     use std::os::unix::io::AsRawFd;
 
+    // The kernel will not handle more than UIO_MAXIOV messages per call.
+    const MAX_MSGS: usize = 1024;
+
+    fn mmsghdr<const N: usize>(iovecs: &mut [libc::iovec; N]) -> libc::mmsghdr {
+        // SAFETY: mmsghdr is plain old data, all zeros is a valid empty message.
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = ptr::null_mut();
+        msg.msg_hdr.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_hdr.msg_iovlen = N as _;
+        msg
+    }
+
+    impl MMsgExt for UdpSocket {
+        fn sendmmsgv(&self, msgs: &[[&[u8]; VEC_SIZE]]) -> io::Result<usize> {
+            let msgs = &msgs[..cmp::min(msgs.len(), MAX_MSGS)];
+            let mut iovecs: Vec<[libc::iovec; VEC_SIZE]> = msgs
+                .iter()
+                .map(|bufs| {
+                    [
+                        libc::iovec {
+                            iov_base: bufs[0].as_ptr().cast_mut().cast(),
+                            iov_len: bufs[0].len(),
+                        },
+                        libc::iovec {
+                            iov_base: bufs[1].as_ptr().cast_mut().cast(),
+                            iov_len: bufs[1].len(),
+                        },
+                    ]
+                })
+                .collect();
+            let mut hdrs: Vec<libc::mmsghdr> = iovecs.iter_mut().map(mmsghdr).collect();
+
+            // SAFETY: All params are setup in this function safely.
+            #[allow(clippy::cast_possible_truncation)] // SAFETY: Length is at most MAX_MSGS.
+            let r =
+                unsafe { libc::sendmmsg(self.as_raw_fd(), hdrs.as_mut_ptr(), hdrs.len() as _, 0) };
+
+            if r < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(r.unsigned_abs() as usize)
+            }
+        }
+
+        fn recvmmsgv<'a, I>(
+            &self,
+            msgs: I,
+            lens: &mut [usize],
+            buffers: &mut MMsgBuffers,
+        ) -> io::Result<usize>
+        where
+            I: IntoIterator<Item = [&'a mut [u8]; RECV_VEC_SIZE]>,
+        {
+            let count = cmp::min(lens.len(), MAX_MSGS);
+            let MMsgBuffers { iovecs, hdrs } = buffers;
+            // The headers point into `iovecs`, so it is filled first.
+            iovecs.clear();
+            iovecs.extend(
+                msgs.into_iter()
+                    .take(count)
+                    .map(|mut bufs| recv_iovecs(&mut bufs)),
+            );
+            hdrs.clear();
+            hdrs.extend(iovecs.iter_mut().map(mmsghdr));
+
+            // SAFETY: All params are setup in this function safely.
+            #[allow(clippy::cast_possible_truncation)] // SAFETY: Length is at most MAX_MSGS.
+            let r = unsafe {
+                libc::recvmmsg(
+                    self.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    hdrs.len() as _,
+                    libc::MSG_WAITFORONE as _,
+                    ptr::null_mut(),
+                )
+            };
+
+            if r < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let received = r.unsigned_abs() as usize;
+            for (len, hdr) in lens.iter_mut().zip(&hdrs[..received]) {
+                *len = hdr.msg_len as usize;
+            }
+            Ok(received)
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{io, recv_iovecs, IOVecExt, SocketAddr, UdpSocket, RECV_VEC_SIZE, VEC_SIZE};
+    use core::{mem, ptr};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddrV6},
+        os::unix::io::AsRawFd,
+    };
+
+    fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match libc::c_int::from(storage.ss_family) {
+            libc::AF_INET => {
+                // SAFETY: The family says this is a sockaddr_in, which fits in the storage.
+                let sin = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in>() };
+                Ok(SocketAddr::from((
+                    Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: The family says this is a sockaddr_in6, which fits in the storage.
+                let sin6 = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in6>() };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
     impl IOVecExt for UdpSocket {
         fn writev(&self, bufs: [&[u8]; VEC_SIZE]) -> io::Result<usize> {
             let iovecs: [libc::iovec; VEC_SIZE] = [
@@ -37,22 +219,14 @@ mod imp {
             }
         }
 
-        fn readv(&self, bufs: [&mut [u8]; VEC_SIZE]) -> io::Result<usize> {
-            let mut iovecs: [libc::iovec; VEC_SIZE] = [
-                libc::iovec {
-                    iov_base: bufs[0].as_mut_ptr().cast(),
-                    iov_len: bufs[0].len(),
-                },
-                libc::iovec {
-                    iov_base: bufs[1].as_mut_ptr().cast(),
-                    iov_len: bufs[1].len(),
-                },
-            ];
+        fn readv(&self, mut bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<usize> {
+            let mut iovecs = recv_iovecs(&mut bufs);
 
             // SAFETY: All params are setup in this function safely.
-            #[allow(clippy::cast_possible_truncation)] // SAFETY: Length is always VEC_SIZE.
+            #[allow(clippy::cast_possible_truncation)] // SAFETY: Length is always RECV_VEC_SIZE.
             #[allow(clippy::cast_possible_wrap)]
-            let r = unsafe { libc::readv(self.as_raw_fd(), iovecs.as_mut_ptr(), VEC_SIZE as _) };
+            let r =
+                unsafe { libc::readv(self.as_raw_fd(), iovecs.as_mut_ptr(), RECV_VEC_SIZE as _) };
 
             if r < 0 {
                 Err(io::Error::last_os_error())
@@ -60,15 +234,102 @@ mod imp {
                 Ok(r.unsigned_abs())
             }
         }
+
+        fn readv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)> {
+            recvmsg(self, bufs, 0)
+        }
+
+        fn peekv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)> {
+            recvmsg(self, bufs, libc::MSG_PEEK)
+        }
+    }
+
+    fn recvmsg(
+        socket: &UdpSocket,
+        mut bufs: [&mut [u8]; RECV_VEC_SIZE],
+        flags: libc::c_int,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let mut iovecs = recv_iovecs(&mut bufs);
+        // SAFETY: sockaddr_storage and msghdr are plain old data, all zeros is valid.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        // SAFETY: See above.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = ptr::addr_of_mut!(storage).cast();
+        #[allow(clippy::cast_possible_truncation)] // SAFETY: A sockaddr is always small.
+        let name_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_namelen = name_len;
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = RECV_VEC_SIZE as _;
+
+        // SAFETY: All params are setup in this function safely.
+        let r = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok((r.unsigned_abs(), socket_addr(&storage)?))
+        }
     }
 }
 
 #[cfg(windows)]
 mod imp {
-    use super::{io, IOVecExt, UdpSocket, VEC_SIZE};
+    use super::{io, IOVecExt, SocketAddr, UdpSocket, RECV_VEC_SIZE, VEC_SIZE};
     use crate::Error;
-    use std::{os::windows::io::AsRawSocket, ptr};
-    use windows_sys::Win32::Networking::WinSock::{WSARecv, WSASend, WSABUF};
+    use core::mem;
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddrV6},
+        os::windows::io::AsRawSocket,
+        ptr,
+    };
+    use windows_sys::Win32::Networking::WinSock::{
+        WSARecv, WSARecvFrom, WSASend, AF_INET, AF_INET6, MSG_PEEK, SOCKADDR_IN, SOCKADDR_IN6,
+        SOCKADDR_STORAGE, WSABUF,
+    };
+
+    fn recv_wsabufs(bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<[WSABUF; RECV_VEC_SIZE]> {
+        let mut wsabufs = [WSABUF {
+            len: 0,
+            buf: ptr::null_mut(),
+        }; RECV_VEC_SIZE];
+        for (wsabuf, buf) in wsabufs.iter_mut().zip(bufs) {
+            wsabuf.len = buf
+                .len()
+                .try_into()
+                .map_err(|_| Error::WinUDP4GiBLimit { size: buf.len() }.into_io())?;
+            wsabuf.buf = buf.as_mut_ptr();
+        }
+        Ok(wsabufs)
+    }
+
+    fn socket_addr(storage: &SOCKADDR_STORAGE) -> io::Result<SocketAddr> {
+        match storage.ss_family {
+            AF_INET => {
+                // SAFETY: The family says this is a SOCKADDR_IN, which fits in the storage.
+                let sin = unsafe { &*ptr::addr_of!(*storage).cast::<SOCKADDR_IN>() };
+                // SAFETY: Every variant of the union is a plain IPv4 address.
+                let addr = unsafe { sin.sin_addr.S_un.S_addr };
+                Ok(SocketAddr::from((
+                    Ipv4Addr::from(addr.to_ne_bytes()),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            AF_INET6 => {
+                // SAFETY: The family says this is a SOCKADDR_IN6, which fits in the storage.
+                let sin6 = unsafe { &*ptr::addr_of!(*storage).cast::<SOCKADDR_IN6>() };
+                // SAFETY: Every variant of the unions is plain old data.
+                let (addr, scope_id) =
+                    unsafe { (sin6.sin6_addr.u.Byte, sin6.Anonymous.sin6_scope_id) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    scope_id,
+                )))
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
 
     impl IOVecExt for UdpSocket {
         fn writev(&self, bufs: [&[u8]; VEC_SIZE]) -> io::Result<usize> {
@@ -124,37 +385,13 @@ mod imp {
             }
         }
 
-        fn readv(&self, bufs: [&mut [u8]; VEC_SIZE]) -> io::Result<usize> {
-            let bufs_lens: [u32; VEC_SIZE] = [
-                bufs[0].len().try_into().map_err(|_| {
-                    Error::WinUDP4GiBLimit {
-                        size: bufs[0].len(),
-                    }
-                    .into_io()
-                })?,
-                bufs[1].len().try_into().map_err(|_e| {
-                    Error::WinUDP4GiBLimit {
-                        size: bufs[1].len(),
-                    }
-                    .into_io()
-                })?,
-            ];
-
-            let mut wsabufs: [WSABUF; VEC_SIZE] = [
-                WSABUF {
-                    len: bufs_lens[0],
-                    buf: bufs[0].as_mut_ptr(),
-                },
-                WSABUF {
-                    len: bufs_lens[1],
-                    buf: bufs[1].as_mut_ptr(),
-                },
-            ];
+        fn readv(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<usize> {
+            let mut wsabufs = recv_wsabufs(bufs)?;
 
             let mut recved: u32 = 0;
             let mut flags: u32 = 0;
             // SAFETY: All params are setup in this function safely.
-            // SAFETY: Length is always VEC_SIZE.
+            // SAFETY: Length is always RECV_VEC_SIZE.
             // SAFETY: On 32 bit systems self.as_raw_socket() returns a u32.
             //         (https://doc.rust-lang.org/src/std/os/windows/raw.rs.html#16)
             #[allow(clippy::cast_possible_truncation)]
@@ -163,7 +400,7 @@ mod imp {
                 WSARecv(
                     self.as_raw_socket() as _,
                     wsabufs.as_mut_ptr(),
-                    VEC_SIZE as _,
+                    RECV_VEC_SIZE as _,
                     &mut recved,
                     &mut flags,
                     ptr::null_mut(),
@@ -177,5 +414,55 @@ mod imp {
                 Err(io::Error::last_os_error())
             }
         }
+
+        fn readv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)> {
+            recv_from(self, bufs, 0)
+        }
+
+        fn peekv_from(&self, bufs: [&mut [u8]; RECV_VEC_SIZE]) -> io::Result<(usize, SocketAddr)> {
+            #[allow(clippy::cast_sign_loss)] // SAFETY: MSG_PEEK is a positive flag.
+            let flags = MSG_PEEK as u32;
+            recv_from(self, bufs, flags)
+        }
+    }
+
+    fn recv_from(
+        socket: &UdpSocket,
+        bufs: [&mut [u8]; RECV_VEC_SIZE],
+        mut flags: u32,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let wsabufs = recv_wsabufs(bufs)?;
+
+        // SAFETY: SOCKADDR_STORAGE is plain old data, all zeros is valid.
+        let mut storage: SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+        #[allow(clippy::cast_possible_truncation)] // SAFETY: A sockaddr is always small.
+        #[allow(clippy::cast_possible_wrap)]
+        let mut storage_len = mem::size_of::<SOCKADDR_STORAGE>() as i32;
+        let mut recved: u32 = 0;
+        // SAFETY: All params are setup in this function safely.
+        // SAFETY: Length is always RECV_VEC_SIZE.
+        // SAFETY: On 32 bit systems socket.as_raw_socket() returns a u32.
+        //         (https://doc.rust-lang.org/src/std/os/windows/raw.rs.html#16)
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_possible_wrap)]
+        let r = unsafe {
+            WSARecvFrom(
+                socket.as_raw_socket() as _,
+                wsabufs.as_ptr(),
+                RECV_VEC_SIZE as _,
+                &mut recved,
+                &mut flags,
+                ptr::addr_of_mut!(storage).cast(),
+                &mut storage_len,
+                ptr::null_mut(),
+                None,
+            )
+        };
+
+        if r == 0 {
+            Ok((recved as usize, socket_addr(&storage)?))
+        } else {
+            Err(io::Error::last_os_error())
+        }
     }
 }
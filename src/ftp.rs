@@ -0,0 +1,262 @@
+//! FTP active mode data connections through a BIND request.
+
+use crate::{
+    tunnel::Listener, Error, Socks4Listener, Socks5Listener, TargetAddr, ToResolvedAddrs,
+    ToTargetAddr,
+};
+use core::time::Duration;
+use std::{
+    io,
+    net::{SocketAddr, SocketAddrV4, TcpStream},
+};
+
+/// The data connection of an FTP transfer in active mode, accepted by the
+/// proxy.
+///
+/// Issues a BIND request and formats the address the proxy listens on as the
+/// argument of `PORT` or `EPRT`. After the command is sent on the control
+/// connection and the transfer is started, `accept` returns the data connection.
+#[derive(Debug)]
+pub struct FtpActiveData {
+    listener: Listener,
+    addr: SocketAddr,
+}
+
+impl FtpActiveData {
+    /// Issues a BIND request through a SOCKS4 proxy for a data connection from
+    /// `server`, the address of the FTP server.
+    ///
+    /// # Notes
+    /// See `Socks4Listener::bind()`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn bind_socks4<T, U>(
+        proxy: T,
+        server: &U,
+        userid: &str,
+        connect_timeout: Option<Duration>,
+    ) -> io::Result<Self>
+    where
+        T: ToResolvedAddrs,
+        U: ToTargetAddr,
+    {
+        Socks4Listener::bind(proxy, server, userid, connect_timeout).and_then(Self::try_from)
+    }
+
+    /// Issues a BIND request through a SOCKS5 proxy for a data connection from
+    /// `server`, the address of the FTP server.
+    ///
+    /// # Notes
+    /// See `Socks5Listener::bind()`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn bind_socks5<T, U>(
+        proxy: T,
+        server: &U,
+        connect_timeout: Option<Duration>,
+    ) -> io::Result<Self>
+    where
+        T: ToResolvedAddrs,
+        U: ToTargetAddr,
+    {
+        Socks5Listener::bind(proxy, server, connect_timeout).and_then(Self::try_from)
+    }
+
+    /// Issues a BIND request through a SOCKS5 proxy using given username and
+    /// password.
+    ///
+    /// # Notes
+    /// See `Socks5Listener::bind_with_password()`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn bind_socks5_with_password<T, U>(
+        proxy: T,
+        server: &U,
+        username: &str,
+        password: &str,
+        connect_timeout: Option<Duration>,
+    ) -> io::Result<Self>
+    where
+        T: ToResolvedAddrs,
+        U: ToTargetAddr,
+    {
+        Socks5Listener::bind_with_password(proxy, server, username, password, connect_timeout)
+            .and_then(Self::try_from)
+    }
+
+    fn new(listener: Listener) -> io::Result<Self> {
+        let addr = listener.advertised_socket_addr()?;
+        Ok(Self { listener, addr })
+    }
+
+    /// The address of the proxy-side TCP listener, which the FTP server should
+    /// connect to.
+    #[must_use]
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The argument of `PORT`, as in `h1,h2,h3,h4,p1,p2`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::InvalidInput, socks2::Error::FtpPortNoIPv6)`
+    pub fn port_argument(&self) -> io::Result<String> {
+        match self.addr {
+            SocketAddr::V4(addr) => Ok(port_argument(addr)),
+            SocketAddr::V6(addr) => Err(Error::FtpPortNoIPv6 { addr }.into_io()),
+        }
+    }
+
+    /// The argument of `EPRT`, as in `|1|addr|port|` or `|2|addr|port|`.
+    #[must_use]
+    pub fn eprt_argument(&self) -> String {
+        let protocol = if self.addr.is_ipv4() { 1 } else { 2 };
+        format!("|{protocol}|{}|{}|", self.addr.ip(), self.addr.port())
+    }
+
+    /// The command to send on the control connection, `PORT` for an IPv4
+    /// address and `EPRT` for an IPv6 address, without the line ending.
+    #[must_use]
+    pub fn command(&self) -> String {
+        match self.addr {
+            SocketAddr::V4(addr) => format!("PORT {}", port_argument(addr)),
+            SocketAddr::V6(_) => format!("EPRT {}", self.eprt_argument()),
+        }
+    }
+
+    /// Waits for the FTP server to open the data connection.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn accept(self) -> io::Result<(TcpStream, TargetAddr)> {
+        self.listener.accept()
+    }
+
+    /// Like `accept`, but gives up after `timeout`.
+    ///
+    /// # Notes
+    /// See `Socks5Listener::accept_timeout()`.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::TimedOut`
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<(TcpStream, TargetAddr)> {
+        self.listener.accept_timeout(timeout)
+    }
+}
+
+fn port_argument(addr: SocketAddrV4) -> String {
+    let [h1, h2, h3, h4] = addr.ip().octets();
+    let [p1, p2] = addr.port().to_be_bytes();
+    format!("{h1},{h2},{h3},{h4},{p1},{p2}")
+}
+
+impl TryFrom<Socks4Listener> for FtpActiveData {
+    type Error = io::Error;
+
+    fn try_from(listener: Socks4Listener) -> io::Result<Self> {
+        Self::new(Listener::V4(listener))
+    }
+}
+
+impl TryFrom<Socks5Listener> for FtpActiveData {
+    type Error = io::Error;
+
+    fn try_from(listener: Socks5Listener) -> io::Result<Self> {
+        Self::new(Listener::V5(listener))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::{mock, unwrap_io_to_socks2_error};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, ToSocketAddrs},
+        thread,
+    };
+
+    // Parses the argument of a PORT command.
+    fn parse_port(argument: &str) -> SocketAddr {
+        let n: Vec<u8> = argument.split(',').map(|n| n.parse().unwrap()).collect();
+        let port = u16::from_be_bytes([n[4], n[5]]);
+        SocketAddr::from(([n[0], n[1], n[2], n[3]], port))
+    }
+
+    #[test]
+    fn active_data() {
+        // an FTP-like server that opens the data connection given with PORT
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let (control, _) = server.accept().unwrap();
+            let mut lines = BufReader::new(control.try_clone().unwrap());
+            let mut line = String::new();
+            lines.read_line(&mut line).unwrap();
+            let argument = line.trim_end().strip_prefix("PORT ").unwrap();
+            (&control)
+                .write_all(b"200 PORT command successful\r\n")
+                .unwrap();
+
+            let mut data = TcpStream::connect(parse_port(argument)).unwrap();
+            data.write_all(b"file contents").unwrap();
+        });
+
+        // a proxy that listens for real and passes the data connection on
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            mock::reply(&mut stream, 0, listener.local_addr().unwrap());
+            let (mut data, peer) = listener.accept().unwrap();
+            mock::reply(&mut stream, 0, peer);
+            io::copy(&mut data, &mut stream).unwrap();
+        });
+
+        let data = FtpActiveData::bind_socks5(proxy, &server_addr, None).unwrap();
+        assert!(data.command().starts_with("PORT 127,0,0,1,"));
+        assert_eq!(
+            data.eprt_argument(),
+            format!("|1|127.0.0.1|{}|", data.addr().port())
+        );
+
+        let mut control = TcpStream::connect(server_addr).unwrap();
+        write!(control, "{}\r\n", data.command()).unwrap();
+        let mut line = String::new();
+        BufReader::new(&control).read_line(&mut line).unwrap();
+        assert!(line.starts_with("200 "));
+
+        let (mut stream, peer) = data.accept().unwrap();
+        assert_eq!(
+            peer.to_socket_addrs().unwrap().next().unwrap().ip(),
+            server_addr.ip()
+        );
+        let mut contents = String::new();
+        stream.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "file contents");
+    }
+
+    #[test]
+    fn eprt_ipv6() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "[2001:db8::7]:4000".parse().unwrap());
+            mock::drain(&mut stream);
+        });
+
+        let data = FtpActiveData::bind_socks5(proxy, &"[2001:db8::1]:21", None).unwrap();
+        assert_eq!(data.command(), "EPRT |2|2001:db8::7|4000|");
+        assert_eq!(
+            unwrap_io_to_socks2_error(&data.port_argument().unwrap_err()),
+            Some(&Error::FtpPortNoIPv6 {
+                addr: "[::1]:0".parse().unwrap()
+            })
+        );
+    }
+}
@@ -10,46 +10,86 @@
 
 extern crate alloc;
 
-use alloc::vec;
+use alloc::{sync::Arc, vec};
+#[cfg(feature = "bind")]
+use core::str::FromStr;
+#[cfg(feature = "bind")]
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
+#[cfg(feature = "bind")]
+use std::net::Shutdown;
 use std::{
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream,
+        ToSocketAddrs,
+    },
 };
 
 #[cfg(feature = "client")]
-pub use v4::client::Socks4Stream;
+pub use auto::{AutoConnector, AutoStream, ProxyProtocol};
+#[cfg(feature = "client")]
+pub use v4::client::{Socks4Resolution, Socks4ResolvedBy, Socks4Stream};
 #[cfg(feature = "client")]
 pub use v5::client::Socks5Stream;
+#[cfg(feature = "client")]
+pub use v5::probe::{probe, probe_with_options, CommandSupport, ProbeOptions, ProbeReport};
+
+#[cfg(feature = "bind")]
+pub use v4::bind::{Socks4AcceptLoop, Socks4Incoming, Socks4Listener};
+#[cfg(feature = "bind")]
+pub use v5::bind::{Socks5AcceptLoop, Socks5Incoming, Socks5Listener};
 
 #[cfg(feature = "bind")]
-pub use v4::bind::Socks4Listener;
+pub use ftp::FtpActiveData;
 #[cfg(feature = "bind")]
-pub use v5::bind::Socks5Listener;
+pub use tunnel::{ReverseTunnel, TunnelEvent, TunnelOptions, TunnelStopHandle};
 
 #[cfg(feature = "udp")]
-pub use v5::udp::Socks5Datagram;
+pub use v5::mux::{MuxOptions, MuxPeer, UdpMux};
+#[cfg(feature = "udp")]
+pub use v5::udp::{
+    DatagramOptions, Socks5Datagram, Socks5DatagramReceiver, Socks5DatagramSender, UdpOverTcp,
+    MIN_REASSEMBLY_TIMEOUT,
+};
 
 pub use error::{is_io_socks2_error, unwrap_io_to_socks2_error, Error};
+pub use resolve::{
+    dns_leak_protection, enable_dns_leak_protection, set_resolver, CachingResolver, Lookup,
+    Resolver, SystemResolver,
+};
 
+#[cfg(feature = "client")]
+mod auto;
 mod error;
 mod ext_bytes;
 #[cfg(feature = "udp")]
 mod ext_io;
+#[cfg(feature = "bind")]
+mod ftp;
+#[cfg(test)]
+mod mock;
+mod resolve;
+#[cfg(all(target_os = "linux", feature = "fast-open"))]
+mod tfo;
+#[cfg(feature = "bind")]
+mod tunnel;
 #[cfg(any(feature = "client", feature = "bind"))]
 mod v4;
 #[cfg(any(feature = "client", feature = "bind", feature = "udp"))]
 mod v5;
 
 /// A description of a connection target.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum TargetAddr {
     /// Connect to an IP address.
     Ip(SocketAddr),
     /// Connect to a fully qualified domain name.
     ///
     /// The domain name will be passed along to the proxy server and DNS lookup
-    /// will happen there.
+    /// will happen there. When resolved locally, the `Resolver` of the
+    /// connection, or the default one set with `set_resolver`, is used, unless
+    /// DNS leak protection forbids it, see `enable_dns_leak_protection`.
     Domain(String, u16),
 }
 
@@ -69,7 +109,7 @@ impl ToSocketAddrs for TargetAddr {
         let inner = match *self {
             Self::Ip(addr) => IterInner::Ip(Some(addr)),
             Self::Domain(ref domain, port) => {
-                let it = (&**domain, port).to_socket_addrs()?;
+                let it = resolve::resolve(domain, port, None)?.into_iter();
                 IterInner::Domain(it)
             }
         };
@@ -195,18 +235,181 @@ impl ToTargetAddr for &str {
     }
 }
 
-fn tcp_stream_connect<T>(proxy: T, connect_timeout: Option<Duration>) -> io::Result<TcpStream>
+/// A trait for the addresses of a proxy or a local socket, like
+/// `ToSocketAddrs`, except that host names are resolved with a `Resolver`.
+///
+/// # Notes
+/// The host name is resolved even with DNS leak protection enabled, since it is
+/// not sent to the proxy.
+pub trait ToResolvedAddrs {
+    /// Resolves the value of `self` to socket addresses, with `resolver` or,
+    /// if `None`, the default one set with `set_resolver`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>>;
+}
+
+macro_rules! to_resolved_addrs_std {
+    ($($t:ty),*) => {
+        $(
+            impl ToResolvedAddrs for $t {
+                fn to_resolved_addrs(&self, _: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+                    // IP addresses only, std does not look anything up
+                    Ok(self.to_socket_addrs()?.collect())
+                }
+            }
+        )*
+    };
+}
+
+to_resolved_addrs_std!(
+    SocketAddr,
+    SocketAddrV4,
+    SocketAddrV6,
+    (IpAddr, u16),
+    (Ipv4Addr, u16),
+    (Ipv6Addr, u16),
+    [SocketAddr]
+);
+
+impl ToResolvedAddrs for TargetAddr {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        match *self {
+            Self::Ip(addr) => Ok(vec![addr]),
+            Self::Domain(ref domain, port) => resolve::lookup(domain, port, resolver),
+        }
+    }
+}
+
+impl ToResolvedAddrs for (&str, u16) {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        self.to_target_addr()?.to_resolved_addrs(resolver)
+    }
+}
+
+impl ToResolvedAddrs for (String, u16) {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        (self.0.as_str(), self.1).to_resolved_addrs(resolver)
+    }
+}
+
+impl ToResolvedAddrs for str {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        self.to_target_addr()?.to_resolved_addrs(resolver)
+    }
+}
+
+impl ToResolvedAddrs for String {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        self.as_str().to_resolved_addrs(resolver)
+    }
+}
+
+impl<T: ToResolvedAddrs + ?Sized> ToResolvedAddrs for &T {
+    fn to_resolved_addrs(&self, resolver: Option<&dyn Resolver>) -> io::Result<Vec<SocketAddr>> {
+        (**self).to_resolved_addrs(resolver)
+    }
+}
+
+/// Options for the TCP connection to the proxy.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    timeout: Option<Duration>,
+    fast_open: bool,
+    resolve: resolve::Settings,
+}
+
+impl ConnectOptions {
+    /// Creates the default options.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            timeout: None,
+            fast_open: false,
+            resolve: resolve::Settings::new(),
+        }
+    }
+
+    /// Sets the connect timeout, which is applied to every socket address tried.
+    ///
+    /// This is the same as passing `connect_timeout` to the other constructors.
+    #[must_use]
+    pub const fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Uses TCP Fast Open so the first bytes sent to the proxy, the SOCKS
+    /// greeting, are carried in the SYN.
+    ///
+    /// # Notes
+    /// Only has an effect on Linux with the `fast-open` feature enabled. A normal
+    /// connection is made if the kernel does not support `TCP_FASTOPEN_CONNECT`.
+    /// Once the kernel has a cookie for the proxy, the connection is only made
+    /// when the greeting is written, so `timeout` no longer covers it.
+    #[must_use]
+    pub const fn fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    /// Sets the `Resolver` for the host names of the proxy and for domains
+    /// resolved locally for this connection, or uses the default one set with
+    /// `set_resolver` with `None`, which is the default.
+    #[must_use]
+    pub fn resolver(mut self, resolver: Option<Arc<dyn Resolver>>) -> Self {
+        self.resolve.set_resolver(resolver);
+        self
+    }
+
+    /// Forbids resolving domain names locally for this connection, like
+    /// `enable_dns_leak_protection` does for the whole process.
+    ///
+    /// # Notes
+    /// Disabling it here does not lift the protection of the process.
+    #[must_use]
+    pub fn dns_leak_protection(mut self, enabled: bool) -> Self {
+        self.resolve.set_leak_protection(enabled);
+        self
+    }
+
+    pub(crate) const fn resolve_settings(&self) -> &resolve::Settings {
+        &self.resolve
+    }
+}
+
+impl From<Option<Duration>> for ConnectOptions {
+    fn from(timeout: Option<Duration>) -> Self {
+        Self::new().timeout(timeout)
+    }
+}
+
+fn tcp_stream_connect<T>(proxy: T, options: &ConnectOptions) -> io::Result<TcpStream>
 where
-    T: ToSocketAddrs,
+    T: ToResolvedAddrs,
 {
-    match connect_timeout {
-        None => TcpStream::connect(proxy),
+    let addrs = proxy.to_resolved_addrs(options.resolve.resolver())?;
+
+    #[cfg(all(target_os = "linux", feature = "fast-open"))]
+    if options.fast_open {
+        let mut last_err = None;
+        for addr in &addrs {
+            match tfo::connect(addr, options.timeout) {
+                Ok(t) => return Ok(t),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        return Err(last_err.unwrap_or_else(|| Error::NoResolveSocketAddrs {}.into_io()));
+    }
+
+    match options.timeout {
+        None => TcpStream::connect(&addrs[..]),
         Some(t) => {
             // Timeout is applied to every SocketAddr try.
-            let mut addrs = proxy.to_socket_addrs()?;
             let mut last_err = None;
-            for addr in &mut addrs {
-                match TcpStream::connect_timeout(&addr, t) {
+            for addr in &addrs {
+                match TcpStream::connect_timeout(addr, t) {
                     Ok(t) => return Ok(t),
                     Err(err) => last_err = Some(err),
                 }
@@ -216,6 +419,147 @@ where
     }
 }
 
+/// Aborts waiting for the remote process of a BIND request from another
+/// thread, see `Socks5Listener::abort_handle()`.
+#[cfg(feature = "bind")]
+#[derive(Debug)]
+pub struct AbortHandle {
+    socket: TcpStream,
+    aborted: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "bind")]
+impl AbortHandle {
+    const fn new(socket: TcpStream, aborted: Arc<AtomicBool>) -> Self {
+        Self { socket, aborted }
+    }
+
+    /// Closes the control connection. A pending or later accept fails with
+    /// `Error::BindAborted` and the listener can no longer be used.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*`
+    pub fn abort(&self) -> io::Result<()> {
+        self.aborted.store(true, Ordering::Release);
+        self.socket.shutdown(Shutdown::Both)
+    }
+}
+
+/// The host expected to connect to a BIND listener, see
+/// `Socks5Listener::accept_from()`.
+#[cfg(feature = "bind")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExpectedPeer {
+    /// A single IP address.
+    Ip(IpAddr),
+    /// An IP network, given by an address and a prefix length.
+    Cidr(IpAddr, u8),
+    /// A domain name, which is resolved locally if the proxy reports an IP.
+    Domain(String),
+}
+
+#[cfg(feature = "bind")]
+impl ExpectedPeer {
+    /// Checks whether `peer` is the expected host.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*`
+    pub fn matches(&self, peer: &TargetAddr) -> io::Result<bool> {
+        self.matches_with(peer, &resolve::Settings::new())
+    }
+
+    // Like `matches`, resolving an expected domain like the connection.
+    pub(crate) fn matches_with(
+        &self,
+        peer: &TargetAddr,
+        settings: &resolve::Settings,
+    ) -> io::Result<bool> {
+        let ip = match *peer {
+            TargetAddr::Ip(addr) => canonical_ip(addr.ip()),
+            TargetAddr::Domain(ref domain, _) => {
+                return Ok(
+                    matches!(self, Self::Domain(expected) if expected.eq_ignore_ascii_case(domain)),
+                );
+            }
+        };
+
+        match *self {
+            Self::Ip(expected) => Ok(canonical_ip(expected) == ip),
+            Self::Cidr(network, prefix) => Ok(in_network(network, prefix, ip)),
+            Self::Domain(ref domain) => Ok(settings
+                .resolve(domain, 0)?
+                .iter()
+                .any(|addr| canonical_ip(addr.ip()) == ip)),
+        }
+    }
+}
+
+#[cfg(feature = "bind")]
+impl FromStr for ExpectedPeer {
+    type Err = io::Error;
+
+    /// Parses an IP address, a CIDR like `10.0.0.0/8`, or a domain name.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || Error::InvalidExpectedPeer { peer: s.to_owned() }.into_io();
+
+        if let Some((network, prefix)) = s.split_once('/') {
+            let network: IpAddr = network.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            let max = if network.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                return Err(invalid());
+            }
+            return Ok(Self::Cidr(network, prefix));
+        }
+
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::Ip(ip));
+        }
+
+        if s.is_empty() || s.len() > 255 || s.contains(':') {
+            return Err(invalid());
+        }
+        Ok(Self::Domain(s.to_owned()))
+    }
+}
+
+#[cfg(feature = "bind")]
+impl From<IpAddr> for ExpectedPeer {
+    fn from(ip: IpAddr) -> Self {
+        Self::Ip(ip)
+    }
+}
+
+// IPv4-mapped IPv6 addresses compare as IPv4.
+#[cfg(feature = "bind")]
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(feature = "bind")]
+fn in_network(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (
+            u128::from(u32::from(network)),
+            u128::from(u32::from(ip)),
+            32,
+        ),
+        // An IPv4-mapped network like `::ffff:10.0.0.0/104` is compared with
+        // the mapped address, so its prefix counts the 96 leading bits.
+        (IpAddr::V6(network), IpAddr::V4(ip)) => {
+            (u128::from(network), u128::from(ip.to_ipv6_mapped()), 128)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        (IpAddr::V4(_), IpAddr::V6(_)) => return false,
+    };
+    let shift = bits - u32::from(prefix).min(bits);
+    // a shift by 128 would overflow
+    shift == 128 || (network ^ ip) >> shift == 0
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod test {
@@ -239,4 +583,48 @@ mod test {
             TargetAddr::Domain("github.com".to_owned(), 443)
         );
     }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn expected_peer() {
+        let peer = |s: &str| TargetAddr::Ip(s.parse().unwrap());
+
+        let expected: ExpectedPeer = "10.1.0.0/16".parse().unwrap();
+        assert_eq!(
+            expected,
+            ExpectedPeer::Cidr(IpAddr::from([10, 1, 0, 0]), 16)
+        );
+        assert!(expected.matches(&peer("10.1.200.3:80")).unwrap());
+        assert!(expected.matches(&peer("[::ffff:10.1.0.9]:80")).unwrap());
+        assert!(!expected.matches(&peer("10.2.0.1:80")).unwrap());
+        assert!(!expected.matches(&peer("[::1]:80")).unwrap());
+
+        let expected: ExpectedPeer = "::/0".parse().unwrap();
+        assert!(expected.matches(&peer("[2001:db8::1]:80")).unwrap());
+
+        let expected: ExpectedPeer = "::ffff:10.1.0.0/112".parse().unwrap();
+        assert!(expected.matches(&peer("10.1.200.3:80")).unwrap());
+        assert!(expected.matches(&peer("[::ffff:10.1.0.9]:80")).unwrap());
+        assert!(!expected.matches(&peer("10.2.0.1:80")).unwrap());
+        assert!(!expected.matches(&peer("[::1]:80")).unwrap());
+
+        let expected: ExpectedPeer = "192.0.2.7".parse().unwrap();
+        assert!(expected.matches(&peer("192.0.2.7:1")).unwrap());
+        assert!(!expected.matches(&peer("192.0.2.8:1")).unwrap());
+
+        let expected: ExpectedPeer = "localhost".parse().unwrap();
+        assert!(expected.matches(&peer("127.0.0.1:1")).unwrap());
+        assert!(expected
+            .matches(&TargetAddr::Domain("LocalHost".to_owned(), 1))
+            .unwrap());
+
+        for invalid in ["10.0.0.0/33", "10.0.0.0/x", "", "a:b"] {
+            assert_eq!(
+                unwrap_io_to_socks2_error(&invalid.parse::<ExpectedPeer>().unwrap_err()),
+                Some(&Error::InvalidExpectedPeer {
+                    peer: String::new()
+                })
+            );
+        }
+    }
 }
@@ -0,0 +1,193 @@
+//! Minimal in-process SOCKS servers used by tests that cannot rely on dante.
+
+#![allow(clippy::unwrap_used)]
+
+use crate::{ext_bytes::BytesExt, CachingResolver, Lookup, Resolver, SystemResolver};
+use alloc::sync::Arc;
+use core::time::Duration;
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+};
+
+/// Spawns a listener on loopback that runs `f` for each of the next `conns`
+/// accepted connections, in order.
+pub fn spawn<F>(conns: usize, f: F) -> (SocketAddr, JoinHandle<()>)
+where
+    F: FnMut(TcpStream) + Send + 'static,
+{
+    serve(TcpListener::bind("127.0.0.1:0").unwrap(), conns, f)
+}
+
+/// Like `spawn`, but on a listener that was set up by the caller.
+pub fn serve<F>(listener: TcpListener, conns: usize, mut f: F) -> (SocketAddr, JoinHandle<()>)
+where
+    F: FnMut(TcpStream) + Send + 'static,
+{
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming().take(conns) {
+            f(stream.unwrap());
+        }
+    });
+    (addr, handle)
+}
+
+/// A resolver for the names it was created with, which uses the system for
+/// other names and counts the lookups.
+pub struct Hosts(Mutex<Vec<(String, SocketAddr, usize)>>);
+
+impl Hosts {
+    /// Makes each name resolve to the IP of its address.
+    pub fn new(hosts: &[(&str, SocketAddr)]) -> Arc<Self> {
+        let hosts = hosts
+            .iter()
+            .map(|&(name, addr)| (name.to_owned(), addr, 0))
+            .collect();
+        Arc::new(Self(Mutex::new(hosts)))
+    }
+
+    /// Wraps the hosts in a `CachingResolver` that can be set for a connection.
+    pub fn caching(self: &Arc<Self>) -> Arc<dyn Resolver> {
+        let hosts = Arc::clone(self);
+        let resolver = move |host: &str, port| hosts.resolve(host, port);
+        Arc::new(CachingResolver::new(resolver, Duration::from_secs(60)))
+    }
+
+    /// How often `name` was looked up, cached lookups not included.
+    pub fn lookups(&self, name: &str) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(host, _, _)| host == name)
+            .map_or(0, |(_, _, lookups)| *lookups)
+    }
+}
+
+impl Resolver for Hosts {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Lookup> {
+        let mut hosts = self.0.lock().unwrap();
+        match hosts.iter_mut().find(|(name, _, _)| name == host) {
+            Some((_, addr, lookups)) => {
+                *lookups += 1;
+                Ok(Lookup::new(vec![SocketAddr::new(addr.ip(), port)]))
+            }
+            None => SystemResolver.resolve(host, port),
+        }
+    }
+}
+
+/// Reads a SOCKS5 greeting and returns the offered methods.
+pub fn read_greeting(stream: &mut TcpStream) -> Vec<u8> {
+    assert_eq!(stream.read_be_u8().unwrap(), 5);
+    let count = stream.read_be_u8().unwrap();
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).unwrap();
+    methods
+}
+
+/// Reads a SOCKS5 greeting and selects "no authentication".
+pub fn greet(stream: &mut TcpStream) {
+    read_greeting(stream);
+    stream.write_all(&[5, 0]).unwrap();
+}
+
+/// Reads a SOCKS5 request, returning the command and the raw address bytes
+/// (including the address type).
+pub fn read_request(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 3];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[0], 5);
+    (head[1], read_addr(stream))
+}
+
+/// Reads a SOCKS5 address, returning its raw bytes (including the address type).
+pub fn read_addr(stream: &mut TcpStream) -> Vec<u8> {
+    let atyp = stream.read_be_u8().unwrap();
+    let len = match atyp {
+        1 => 4,
+        3 => stream.read_be_u8().unwrap() as usize,
+        4 => 16,
+        _ => panic!("bad address type {atyp}"),
+    };
+    let mut addr = vec![0; len + 2];
+    stream.read_exact(&mut addr).unwrap();
+    let mut raw = vec![atyp];
+    if atyp == 3 {
+        raw.push(u8::try_from(len).unwrap());
+    }
+    raw.extend(addr);
+    raw
+}
+
+/// Encodes a SOCKS5 IP address.
+pub fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = vec![];
+    match addr {
+        SocketAddr::V4(addr) => {
+            out.push(1);
+            out.extend(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            out.push(4);
+            out.extend(addr.ip().octets());
+        }
+    }
+    out.extend(addr.port().to_be_bytes());
+    out
+}
+
+/// Decodes raw SOCKS5 IP address bytes as produced by `read_addr`.
+pub fn decode_addr(raw: &[u8]) -> SocketAddr {
+    match raw[0] {
+        1 => {
+            let ip: [u8; 4] = raw[1..5].try_into().unwrap();
+            let port = u16::from_be_bytes([raw[5], raw[6]]);
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+        }
+        4 => {
+            let ip: [u8; 16] = raw[1..17].try_into().unwrap();
+            let port = u16::from_be_bytes([raw[17], raw[18]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+        }
+        atyp => panic!("not an ip address type {atyp}"),
+    }
+}
+
+/// Writes a SOCKS5 reply.
+pub fn reply(stream: &mut TcpStream, code: u8, addr: SocketAddr) {
+    let mut packet = vec![5, code, 0];
+    packet.extend(encode_addr(addr));
+    stream.write_all(&packet).unwrap();
+}
+
+/// Builds a SOCKS5 UDP datagram with the given fragment position.
+#[cfg_attr(not(feature = "udp"), allow(dead_code))]
+pub fn udp_packet(frag: u8, addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0, 0, frag];
+    packet.extend(encode_addr(addr));
+    packet.extend(payload);
+    packet
+}
+
+/// Builds a UDP over TCP packet, with the address types numbered from 0.
+#[cfg_attr(not(feature = "udp"), allow(dead_code))]
+pub fn uot_packet(addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = encode_addr(addr);
+    packet[0] = match addr {
+        SocketAddr::V4(_) => 0,
+        SocketAddr::V6(_) => 1,
+    };
+    packet.extend(u16::try_from(payload.len()).unwrap().to_be_bytes());
+    packet.extend(payload);
+    packet
+}
+
+/// Blocks until the peer closes the connection.
+pub fn drain(stream: &mut TcpStream) {
+    let mut buf = [0; 64];
+    while matches!(stream.read(&mut buf), Ok(n) if n > 0) {}
+}
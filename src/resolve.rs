@@ -0,0 +1,310 @@
+//! Local name resolution used for `TargetAddr` domains.
+
+use crate::Error;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Mutex, PoisonError, RwLock},
+    time::Instant,
+};
+
+static RESOLVER: RwLock<Option<Arc<dyn Resolver>>> = RwLock::new(None);
+static LEAK_PROTECTION: AtomicBool = AtomicBool::new(false);
+
+// (host, port) to the addresses and when they expire
+type Entries = HashMap<(String, u16), (Vec<SocketAddr>, Instant)>;
+
+/// The result of resolving a host name.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Lookup {
+    addrs: Vec<SocketAddr>,
+    ttl: Option<Duration>,
+}
+
+impl Lookup {
+    /// Creates a lookup result without a known time to live.
+    #[must_use]
+    pub const fn new(addrs: Vec<SocketAddr>) -> Self {
+        Self { addrs, ttl: None }
+    }
+
+    /// Sets how long the result may be cached, see `CachingResolver`.
+    #[must_use]
+    pub const fn ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The resolved socket addresses.
+    #[must_use]
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// How long the result may be cached, if known.
+    #[must_use]
+    pub const fn time_to_live(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+/// Resolves host names locally.
+///
+/// Closures taking the host and port are resolvers too.
+pub trait Resolver: Send + Sync {
+    /// Resolves `host` to socket addresses with `port`.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*`
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Lookup>;
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str, u16) -> io::Result<Lookup> + Send + Sync,
+{
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Lookup> {
+        self(host, port)
+    }
+}
+
+/// Resolves with the operating system, through `std::net::ToSocketAddrs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Lookup> {
+        Ok(Lookup::new((host, port).to_socket_addrs()?.collect()))
+    }
+}
+
+/// Caches the results of another `Resolver`.
+///
+/// Results are kept for their time to live, or for `default_ttl` if the
+/// resolver does not know it. Failed lookups are not cached.
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    default_ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Caches the results of `inner`.
+    #[must_use]
+    pub fn new(inner: R, default_ttl: Duration) -> Self {
+        Self {
+            inner,
+            default_ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Removes all cached results.
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Lookup> {
+        let key = (host.to_ascii_lowercase(), port);
+        let now = Instant::now();
+        let cached = self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(addrs, expires)| Lookup::new(addrs.clone()).ttl(Some(*expires - now)));
+        if let Some(lookup) = cached {
+            return Ok(lookup);
+        }
+
+        // Not resolved under the lock, so a slow lookup does not block others.
+        let lookup = self.inner.resolve(host, port)?;
+        let ttl = lookup.time_to_live().unwrap_or(self.default_ttl);
+        if let Some(expires) = now.checked_add(ttl) {
+            self.entries
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, (lookup.addrs.clone(), expires));
+        }
+        Ok(lookup)
+    }
+}
+
+/// Sets the default `Resolver`, or goes back to the `SystemResolver` with
+/// `None`.
+///
+/// # Notes
+/// The default applies to the whole process, to every connection without a
+/// resolver of its own, see `ConnectOptions::resolver()`, and to `TargetAddr`'s
+/// `ToSocketAddrs`.
+pub fn set_resolver(resolver: Option<Arc<dyn Resolver>>) {
+    *RESOLVER.write().unwrap_or_else(PoisonError::into_inner) = resolver;
+}
+
+/// Forbids resolving domain names locally for the whole process, so they only
+/// reach the proxy.
+///
+/// # Notes
+/// Once enabled, leak protection stays enabled for the rest of the process, so
+/// no part of a program can turn it off for another. Use
+/// `ConnectOptions::dns_leak_protection()` to enable it for some connections
+/// only.
+///
+/// Every path that would resolve a domain with the `Resolver` fails with
+/// `Error::LocalDnsRefused` instead, including `TargetAddr`'s `ToSocketAddrs`,
+/// `ExpectedPeer::matches()` and a domain in a BIND or UDP ASSOCIATE reply.
+/// SOCKS5 sends domain targets to the proxy, and SOCKS4 sends them with
+/// SOCKS4A, which the proxy has to support.
+///
+/// The host name of a proxy or local address is still resolved locally, with
+/// the `Resolver`, pass its IP to avoid that lookup too.
+pub fn enable_dns_leak_protection() {
+    LEAK_PROTECTION.store(true, Ordering::Release);
+}
+
+/// Whether resolving domain names locally is forbidden for the whole process,
+/// see `enable_dns_leak_protection`.
+#[must_use]
+pub fn dns_leak_protection() -> bool {
+    LEAK_PROTECTION.load(Ordering::Acquire)
+}
+
+/// How one connection resolves domain names locally.
+#[derive(Clone, Default)]
+pub struct Settings {
+    resolver: Option<Arc<dyn Resolver>>,
+    leak_protection: bool,
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("custom_resolver", &self.resolver.is_some())
+            .field("leak_protection", &self.leak_protection)
+            .finish()
+    }
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            resolver: None,
+            leak_protection: false,
+        }
+    }
+
+    pub fn set_resolver(&mut self, resolver: Option<Arc<dyn Resolver>>) {
+        self.resolver = resolver;
+    }
+
+    pub fn set_leak_protection(&mut self, enabled: bool) {
+        self.leak_protection = enabled;
+    }
+
+    // The resolver of the connection, `None` for the default one.
+    pub fn resolver(&self) -> Option<&dyn Resolver> {
+        self.resolver.as_deref()
+    }
+
+    // Resolves a domain that would otherwise reach the proxy, refused with
+    // leak protection of the connection or the process.
+    pub fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if self.leak_protection {
+            return Err(Error::LocalDnsRefused {
+                domain: host.to_owned(),
+            }
+            .into_io());
+        }
+        resolve(host, port, self.resolver())
+    }
+}
+
+pub fn resolve(
+    host: &str,
+    port: u16,
+    resolver: Option<&dyn Resolver>,
+) -> io::Result<Vec<SocketAddr>> {
+    if dns_leak_protection() {
+        return Err(Error::LocalDnsRefused {
+            domain: host.to_owned(),
+        }
+        .into_io());
+    }
+    lookup(host, port, resolver)
+}
+
+// Resolves with `resolver`, or the default one, regardless of leak protection,
+// for the proxy and local addresses, which are never sent to a proxy.
+pub fn lookup(
+    host: &str,
+    port: u16,
+    resolver: Option<&dyn Resolver>,
+) -> io::Result<Vec<SocketAddr>> {
+    if let Some(resolver) = resolver {
+        return Ok(resolver.resolve(host, port)?.addrs);
+    }
+
+    let default = RESOLVER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let lookup = match default {
+        Some(resolver) => resolver.resolve(host, port)?,
+        None => SystemResolver.resolve(host, port)?,
+    };
+    Ok(lookup.addrs)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn caching_resolver() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let resolver = CachingResolver::new(
+            move |host: &str, port| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let lookup = Lookup::new(vec![SocketAddr::from(([192, 0, 2, 1], port))]);
+                // "short.test" expires right away
+                Ok(lookup.ttl((host == "short.test").then_some(Duration::ZERO)))
+            },
+            Duration::from_secs(60),
+        );
+
+        for _ in 0..3 {
+            let lookup = resolver.resolve("cached.test", 80).unwrap();
+            assert_eq!(lookup.addrs(), ["192.0.2.1:80".parse().unwrap()]);
+        }
+        resolver.resolve("CACHED.test", 80).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        resolver.resolve("cached.test", 443).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        resolver.resolve("short.test", 80).unwrap();
+        resolver.resolve("short.test", 80).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        resolver.clear();
+        resolver.resolve("cached.test", 80).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,161 @@
+//! TCP Fast Open for the connection to the proxy.
+//!
+//! With `TCP_FASTOPEN_CONNECT` the kernel defers the handshake to the first
+//! write when it has a cookie for the server, so the SOCKS greeting goes out in
+//! the SYN. Without a cookie it connects normally and asks for one.
+
+use core::{mem, ptr, time::Duration};
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Instant,
+};
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation)] // SAFETY: The size of an int always fits.
+    let len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value lives for the duration of the call and the length matches it.
+    cvt(unsafe { libc::setsockopt(fd, level, name, ptr::addr_of!(value).cast(), len) }).map(drop)
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: sockaddr_storage is plain old data, all zeros is a valid value.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: sockaddr_storage is large enough and aligned for any sockaddr.
+            let sin = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in>() };
+            #[allow(clippy::cast_possible_truncation)] // SAFETY: Address families fit.
+            let family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_family = family;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: sockaddr_storage is large enough and aligned for any sockaddr.
+            let sin6 = unsafe { &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in6>() };
+            #[allow(clippy::cast_possible_truncation)] // SAFETY: Address families fit.
+            let family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_family = family;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    #[allow(clippy::cast_possible_truncation)] // SAFETY: A sockaddr is always small.
+    let len = len as libc::socklen_t;
+    (storage, len)
+}
+
+/// Connects to `addr` with `TCP_FASTOPEN_CONNECT` set, falling back to a normal
+/// connect if the kernel does not support it.
+pub fn connect(addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: plain syscall, the returned fd is owned right away.
+    let fd = cvt(unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+    // SAFETY: fd is a freshly created socket that nothing else owns.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let stream = TcpStream::from(fd);
+    let raw = stream.as_raw_fd();
+
+    if setsockopt(raw, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1).is_err() {
+        return timeout.map_or_else(
+            || TcpStream::connect(addr),
+            |timeout| TcpStream::connect_timeout(addr, timeout),
+        );
+    }
+
+    let (storage, len) = sockaddr(addr);
+    let connect = || {
+        // SAFETY: storage holds a valid sockaddr of length len.
+        cvt(unsafe { libc::connect(raw, ptr::addr_of!(storage).cast(), len) })
+    };
+
+    let Some(timeout) = timeout else {
+        connect()?;
+        return Ok(stream);
+    };
+    if timeout.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+
+    stream.set_nonblocking(true)?;
+    match connect() {
+        // The handshake was deferred until the first write.
+        Ok(_) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => wait_connected(raw, timeout)?,
+        Err(err) => return Err(err),
+    }
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+fn wait_connected(fd: RawFd, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection timed out",
+            ));
+        }
+        let millis = libc::c_int::try_from(left.as_millis())
+            .unwrap_or(libc::c_int::MAX)
+            .max(1);
+        // SAFETY: pollfd is valid for the duration of the call.
+        match cvt(unsafe { libc::poll(&mut pollfd, 1, millis) }) {
+            Ok(0) => {}
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut err: libc::c_int = 0;
+    #[allow(clippy::cast_possible_truncation)] // SAFETY: The size of an int always fits.
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: err and len are valid for writes of their sizes.
+    cvt(unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            ptr::addr_of_mut!(err).cast(),
+            &mut len,
+        )
+    })?;
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(err))
+    }
+}
@@ -0,0 +1,491 @@
+//! Exposes a local service on the proxy's side through BIND requests.
+
+use crate::{
+    AbortHandle, Socks4AcceptLoop, Socks4Listener, Socks5AcceptLoop, Socks5Listener, TargetAddr,
+};
+use alloc::sync::Arc;
+use core::time::Duration;
+use std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+};
+
+/// Options used when starting a `ReverseTunnel`.
+#[derive(Debug, Clone)]
+pub struct TunnelOptions {
+    pool_size: usize,
+    retry_delay: Duration,
+    connect_timeout: Option<Duration>,
+}
+
+impl Default for TunnelOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TunnelOptions {
+    /// Creates the default options, a pool of 4 BIND requests and a 1 second
+    /// retry delay.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            pool_size: 4,
+            retry_delay: Duration::from_secs(1),
+            connect_timeout: None,
+        }
+    }
+
+    /// Sets how many BIND requests are kept outstanding. At least one is used.
+    #[must_use]
+    pub const fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// Sets how long to wait before a new BIND request after one failed.
+    #[must_use]
+    pub const fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Sets the timeout for connecting to the local service.
+    #[must_use]
+    pub const fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+}
+
+/// What happened in a `ReverseTunnel`, passed to its event callback.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TunnelEvent<'a> {
+    /// A BIND request is waiting on this address, which should be advertised
+    /// to the remote side.
+    Bound(&'a TargetAddr),
+    /// A remote host connected and is relayed to the local service.
+    Accepted(&'a TargetAddr),
+    /// A BIND request, accept, or local connection failed.
+    Error(&'a io::Error),
+}
+
+type Events = dyn Fn(TunnelEvent<'_>) + Send + Sync;
+
+#[derive(Debug)]
+enum Source {
+    V4(Socks4AcceptLoop),
+    V5(Socks5AcceptLoop),
+}
+
+// A BIND listener of either protocol, also used by `FtpActiveData`.
+#[derive(Debug)]
+pub enum Listener {
+    V4(Socks4Listener),
+    V5(Socks5Listener),
+}
+
+impl Source {
+    fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Self::V4(accept_loop) => accept_loop.bind().map(Listener::V4),
+            Self::V5(accept_loop) => accept_loop.bind().map(Listener::V5),
+        }
+    }
+}
+
+impl Listener {
+    pub fn proxy_addr(&self) -> io::Result<TargetAddr> {
+        match self {
+            Self::V4(listener) => listener.proxy_addr().map(TargetAddr::Ip),
+            Self::V5(listener) => Ok(listener.proxy_addr().clone()),
+        }
+    }
+
+    fn abort_handle(&self) -> io::Result<AbortHandle> {
+        match self {
+            Self::V4(listener) => listener.abort_handle(),
+            Self::V5(listener) => listener.abort_handle(),
+        }
+    }
+
+    pub fn advertised_socket_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::V4(listener) => listener.proxy_addr(),
+            Self::V5(listener) => listener.advertised_socket_addr(),
+        }
+    }
+
+    // Returns the connection and the remote host from the second reply.
+    pub fn accept(self) -> io::Result<(TcpStream, TargetAddr)> {
+        match self {
+            Self::V4(listener) => {
+                let stream = listener.accept()?;
+                let peer = TargetAddr::Ip(SocketAddr::V4(stream.proxy_addr()));
+                Ok((stream.into(), peer))
+            }
+            Self::V5(listener) => {
+                let stream = listener.accept()?;
+                let peer = stream.proxy_addr().clone();
+                Ok((stream.into(), peer))
+            }
+        }
+    }
+
+    pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<(TcpStream, TargetAddr)> {
+        match self {
+            Self::V4(listener) => {
+                let stream = listener.accept_timeout(timeout)?;
+                let peer = TargetAddr::Ip(SocketAddr::V4(stream.proxy_addr()));
+                Ok((stream.into(), peer))
+            }
+            Self::V5(listener) => {
+                let stream = listener.accept_timeout(timeout)?;
+                let peer = stream.proxy_addr().clone();
+                Ok((stream.into(), peer))
+            }
+        }
+    }
+}
+
+/// Publishes a local TCP service on the proxy's side.
+///
+/// A pool of BIND requests is kept outstanding, each on a background thread.
+/// Every accepted connection is connected to the local service and relayed in
+/// both directions, with a half-close forwarded to the other side. A new BIND
+/// request replaces each one that accepted or failed.
+///
+/// # Notes
+/// Dropping the tunnel stops it, see `TunnelStopHandle::stop()`.
+pub struct ReverseTunnel {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl core::fmt::Debug for ReverseTunnel {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ReverseTunnel")
+            .field("local", &self.shared.local)
+            .field("workers", &self.workers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Stops a `ReverseTunnel` from another thread.
+#[derive(Clone)]
+pub struct TunnelStopHandle {
+    shared: Arc<Shared>,
+}
+
+impl core::fmt::Debug for TunnelStopHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TunnelStopHandle")
+            .field("local", &self.shared.local)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Shared {
+    source: Source,
+    local: SocketAddr,
+    options: TunnelOptions,
+    events: Box<Events>,
+    state: Mutex<State>,
+    stopped: Condvar,
+}
+
+struct State {
+    stopped: bool,
+    // abort handles of the pending BIND requests, by worker
+    pending: Vec<Option<AbortHandle>>,
+}
+
+impl ReverseTunnel {
+    /// Starts relaying connections accepted through a SOCKS4 proxy to `local`.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*`
+    pub fn socks4<F>(
+        accept_loop: Socks4AcceptLoop,
+        local: SocketAddr,
+        options: &TunnelOptions,
+        events: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(TunnelEvent<'_>) + Send + Sync + 'static,
+    {
+        Self::start(Source::V4(accept_loop), local, options, Box::new(events))
+    }
+
+    /// Starts relaying connections accepted through a SOCKS5 proxy to `local`.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*`
+    pub fn socks5<F>(
+        accept_loop: Socks5AcceptLoop,
+        local: SocketAddr,
+        options: &TunnelOptions,
+        events: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(TunnelEvent<'_>) + Send + Sync + 'static,
+    {
+        Self::start(Source::V5(accept_loop), local, options, Box::new(events))
+    }
+
+    fn start(
+        source: Source,
+        local: SocketAddr,
+        options: &TunnelOptions,
+        events: Box<Events>,
+    ) -> io::Result<Self> {
+        let size = options.pool_size.max(1);
+        let shared = Arc::new(Shared {
+            source,
+            local,
+            options: options.clone(),
+            events,
+            state: Mutex::new(State {
+                stopped: false,
+                pending: (0..size).map(|_| None).collect(),
+            }),
+            stopped: Condvar::new(),
+        });
+
+        let mut tunnel = Self {
+            shared,
+            workers: Vec::with_capacity(size),
+        };
+        for id in 0..size {
+            let shared = Arc::clone(&tunnel.shared);
+            let worker = thread::Builder::new()
+                .name("socks2-tunnel".to_owned())
+                .spawn(move || shared.run(id))?;
+            tunnel.workers.push(worker);
+        }
+        Ok(tunnel)
+    }
+
+    /// Returns a handle that stops the tunnel from another thread.
+    #[must_use]
+    pub fn stop_handle(&self) -> TunnelStopHandle {
+        TunnelStopHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Stops the tunnel, see `TunnelStopHandle::stop()`.
+    pub fn stop(&self) {
+        self.shared.stop();
+    }
+
+    /// Waits for the background threads to finish once the tunnel is stopped.
+    pub fn join(mut self) {
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for ReverseTunnel {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+impl TunnelStopHandle {
+    /// Aborts the pending BIND requests and stops issuing new ones.
+    ///
+    /// # Notes
+    /// Connections that are already relayed are kept until either side closes.
+    pub fn stop(&self) {
+        self.shared.stop();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stop(&self) {
+        let mut state = self.lock();
+        state.stopped = true;
+        for handle in state.pending.iter_mut().filter_map(Option::take) {
+            let _ = handle.abort();
+        }
+        drop(state);
+        self.stopped.notify_all();
+    }
+
+    fn run(&self, id: usize) {
+        loop {
+            match self.accept(id) {
+                Ok(Some((remote, peer))) => {
+                    (self.events)(TunnelEvent::Accepted(&peer));
+                    if let Err(err) = self.relay(remote) {
+                        (self.events)(TunnelEvent::Error(&err));
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    (self.events)(TunnelEvent::Error(&err));
+                    if self.wait_retry() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Issues one BIND request and waits for its connection, `None` once stopped.
+    fn accept(&self, id: usize) -> io::Result<Option<(TcpStream, TargetAddr)>> {
+        let listener = self.source.bind()?;
+        let addr = listener.proxy_addr()?;
+        let handle = listener.abort_handle()?;
+
+        let mut state = self.lock();
+        // Checked under the lock, so `stop` cannot miss this request.
+        if state.stopped {
+            return Ok(None);
+        }
+        state.pending[id] = Some(handle);
+        drop(state);
+
+        (self.events)(TunnelEvent::Bound(&addr));
+        let result = listener.accept();
+
+        let mut state = self.lock();
+        state.pending[id] = None;
+        let stopped = state.stopped;
+        drop(state);
+
+        match result {
+            _ if stopped => Ok(None),
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Returns true if the tunnel was stopped while waiting.
+    fn wait_retry(&self) -> bool {
+        self.stopped
+            .wait_timeout_while(self.lock(), self.options.retry_delay, |state| {
+                !state.stopped
+            })
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+            .stopped
+    }
+
+    fn relay(&self, remote: TcpStream) -> io::Result<()> {
+        let local = match self.options.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.local, timeout)?,
+            None => TcpStream::connect(self.local)?,
+        };
+
+        let (remote_read, local_write) = (remote.try_clone()?, local.try_clone()?);
+        thread::Builder::new()
+            .name("socks2-tunnel-relay".to_owned())
+            .spawn(move || {
+                let _ = copy_half(remote_read, local_write);
+            })?;
+        thread::Builder::new()
+            .name("socks2-tunnel-relay".to_owned())
+            .spawn(move || {
+                let _ = copy_half(local, remote);
+            })?;
+        Ok(())
+    }
+}
+
+// Copies until EOF, then forwards the half-close.
+fn copy_half(mut from: TcpStream, mut to: TcpStream) -> io::Result<u64> {
+    let result = io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+    if result.is_err() {
+        // the other direction cannot continue either
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::mock;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    #[test]
+    fn reverse_tunnel() {
+        // a local service that answers once the request is half-closed
+        let service = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = service.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = service.accept().unwrap();
+            let mut request = vec![];
+            stream.read_to_end(&mut request).unwrap();
+            request.make_ascii_uppercase();
+            stream.write_all(&request).unwrap();
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let mut conn = 0;
+        let (proxy, _) = mock::spawn(2, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:4000".parse().unwrap());
+            if conn == 0 {
+                // the proxy's side of the connection
+                mock::reply(&mut stream, 0, "192.0.2.7:5000".parse().unwrap());
+                stream.write_all(b"hello").unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let mut response = vec![];
+                stream.read_to_end(&mut response).unwrap();
+                tx.send(response).unwrap();
+            } else {
+                // the replacement request, pending until stopped
+                mock::drain(&mut stream);
+            }
+            conn += 1;
+        });
+
+        let (events_tx, events_rx) = mpsc::channel();
+        let events_tx = Mutex::new(events_tx);
+        let accept_loop = Socks5AcceptLoop::new(proxy, &"0.0.0.0:0", None).unwrap();
+        let options = TunnelOptions::new().pool_size(1);
+        let tunnel = ReverseTunnel::socks5(accept_loop, local, &options, move |event| {
+            let event = match event {
+                TunnelEvent::Bound(addr) => format!("bound {addr}"),
+                TunnelEvent::Accepted(addr) => format!("accepted {addr}"),
+                TunnelEvent::Error(err) => format!("error {err}"),
+            };
+            events_tx.lock().unwrap().send(event).unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(rx.recv().unwrap(), b"HELLO");
+        let events: Vec<String> = events_rx.iter().take(3).collect();
+        assert_eq!(
+            events,
+            [
+                "bound 127.0.0.1:4000",
+                "accepted 192.0.2.7:5000",
+                "bound 127.0.0.1:4000"
+            ]
+        );
+
+        tunnel.stop_handle().stop();
+        tunnel.join();
+        assert!(events_rx.try_recv().is_err());
+    }
+}
@@ -3,12 +3,12 @@ use std::{
     io::{
         Read, {self},
     },
-    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+    net::{Ipv4Addr, SocketAddrV4},
 };
 
 const NULL_BYTE: &[u8] = &0_u8.to_be_bytes();
 
-fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
+fn read_response<R: Read>(socket: &mut R) -> io::Result<SocketAddrV4> {
     let mut response = [0u8; 8];
     socket.read_exact(&mut response)?;
     let mut response = &response[..];
@@ -37,22 +37,51 @@ fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
 #[cfg(feature = "client")]
 pub mod client {
     use crate::{
-        tcp_stream_connect,
+        tcp_stream_connect, unwrap_io_to_socks2_error,
         v4::{read_response, NULL_BYTE},
-        Error, TargetAddr, ToTargetAddr,
+        ConnectOptions, Error, TargetAddr, ToResolvedAddrs, ToTargetAddr,
     };
     use core::time::Duration;
+    #[cfg(unix)]
+    use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+    #[cfg(windows)]
+    use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
     use std::{
         io,
-        io::{Read, Write},
-        net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs},
+        io::{IoSlice, IoSliceMut, Read, Write},
+        net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream},
     };
 
+    /// Where a `TargetAddr::Domain` is resolved, see
+    /// `Socks4Stream::connect_with_resolution()`.
+    #[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+    pub enum Socks4Resolution {
+        /// Send the domain to the proxy with SOCKS4A.
+        #[default]
+        Remote,
+        /// Resolve the domain locally and send its IPv4 address with SOCKS4.
+        Local,
+        /// Use SOCKS4A, and resolve locally if the proxy rejects the request.
+        RemoteThenLocal,
+    }
+
+    /// How the target of a `Socks4Stream` was sent to the proxy.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum Socks4ResolvedBy {
+        /// The target was an IP address.
+        Ip,
+        /// The domain was resolved by the proxy with SOCKS4A.
+        Remote,
+        /// The domain was resolved locally.
+        Local,
+    }
+
     /// A SOCKS4 and SOCKS4A client.
     #[derive(Debug)]
     pub struct Socks4Stream {
         pub(super) socket: TcpStream,
         pub(super) proxy_addr: SocketAddrV4,
+        pub(super) resolved_by: Socks4ResolvedBy,
     }
 
     impl Socks4Stream {
@@ -62,7 +91,8 @@ pub mod client {
         /// If `target` is a `TargetAddr::Domain`, the domain name will be forwarded
         /// to the proxy server using the SOCKS4A protocol extension. If the proxy
         /// server does not support SOCKS4A, consider performing the DNS lookup
-        /// locally and passing a `TargetAddr::Ip`.
+        /// locally and passing a `TargetAddr::Ip`. With DNS leak protection
+        /// enabled, the domain is always sent with SOCKS4A.
         ///
         /// When using `connect_timeout` the duration will apply to every socket address
         /// tried. Only the last connection error will be returned or
@@ -77,10 +107,97 @@ pub mod client {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Self::connect_raw(1, proxy, target, userid, &connect_timeout.into())
+        }
+
+        /// Connects to a target server through a SOCKS4 proxy with the given
+        /// `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks4Stream::connect()` and `ConnectOptions::fast_open()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_with_options<T, U>(
+            proxy: T,
+            target: &U,
+            userid: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
-            Self::connect_raw(1, proxy, target, userid, connect_timeout)
+            Self::connect_raw(1, proxy, target, userid, options)
+        }
+
+        /// Connects to a target server through a SOCKS4 proxy, resolving a
+        /// `TargetAddr::Domain` as given by `resolution`.
+        ///
+        /// # Notes
+        /// With `Socks4Resolution::RemoteThenLocal` a new connection to the
+        /// proxy is made after it rejected the SOCKS4A request with code 91,
+        /// which proxies without SOCKS4A support send. `resolved_by` reports
+        /// which one was used. Local resolution uses the first IPv4 address.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::PermissionDenied, socks2::Error::LocalDnsRefused)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_with_resolution<T, U>(
+            proxy: T,
+            target: &U,
+            userid: &str,
+            options: &ConnectOptions,
+            resolution: Socks4Resolution,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let TargetAddr::Domain(ref domain, port) = target.to_target_addr()? else {
+                return Self::connect_raw(1, proxy, target, userid, options);
+            };
+
+            let local = || {
+                let addr = options
+                    .resolve_settings()
+                    .resolve(domain, port)?
+                    .into_iter()
+                    .find(SocketAddr::is_ipv4)
+                    .ok_or_else(|| Error::NoResolveSocketAddrs {}.into_io())?;
+                Ok::<_, io::Error>(addr)
+            };
+
+            match resolution {
+                Socks4Resolution::Remote => Self::connect_raw(1, proxy, target, userid, options),
+                Socks4Resolution::Local => {
+                    let mut stream = Self::connect_raw(1, proxy, &local()?, userid, options)?;
+                    stream.resolved_by = Socks4ResolvedBy::Local;
+                    Ok(stream)
+                }
+                Socks4Resolution::RemoteThenLocal => {
+                    // Resolved once, in case we have to connect again.
+                    let proxy: Vec<SocketAddr> =
+                        proxy.to_resolved_addrs(options.resolve_settings().resolver())?;
+                    match Self::connect_raw(1, &proxy[..], target, userid, options) {
+                        Err(err)
+                            if matches!(
+                                unwrap_io_to_socks2_error(&err),
+                                Some(Error::ConnectionRefused { code: 91 })
+                            ) =>
+                        {
+                            let mut stream =
+                                Self::connect_raw(1, &proxy[..], &local()?, userid, options)?;
+                            stream.resolved_by = Socks4ResolvedBy::Local;
+                            Ok(stream)
+                        }
+                        result => result,
+                    }
+                }
+            }
         }
 
         pub(super) fn connect_raw<T, U>(
@@ -88,18 +205,34 @@ pub mod client {
             proxy: T,
             target: &U,
             userid: &str,
-            connect_timeout: Option<Duration>,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let socket = tcp_stream_connect(proxy, options)?;
+            Self::handshake(socket, command, target, userid)
+        }
+
+        pub(crate) fn handshake<U>(
+            mut socket: TcpStream,
+            command: u8,
+            target: &U,
+            userid: &str,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
             U: ToTargetAddr,
         {
-            let mut socket = tcp_stream_connect(proxy, connect_timeout)?;
             let target = target.to_target_addr()?;
 
             let mut packet = vec![];
             packet.write_all(&4_u8.to_be_bytes())?; // version
             packet.write_all(&command.to_be_bytes())?; // command code
+            let resolved_by = match target {
+                TargetAddr::Ip(_) => Socks4ResolvedBy::Ip,
+                TargetAddr::Domain(..) => Socks4ResolvedBy::Remote,
+            };
             match target.to_target_addr()? {
                 TargetAddr::Ip(addr) => {
                     let addr = match addr {
@@ -126,7 +259,11 @@ pub mod client {
             socket.write_all(&packet)?;
             let proxy_addr = read_response(&mut socket)?;
 
-            Ok(Self { socket, proxy_addr })
+            Ok(Self {
+                socket,
+                proxy_addr,
+                resolved_by,
+            })
         }
 
         /// Returns the proxy-side address of the connection between the proxy and
@@ -136,6 +273,12 @@ pub mod client {
             self.proxy_addr
         }
 
+        /// Returns how the target was sent to the proxy.
+        #[must_use]
+        pub const fn resolved_by(&self) -> Socks4ResolvedBy {
+            self.resolved_by
+        }
+
         /// Returns a shared reference to the inner `TcpStream`.
         #[must_use]
         pub const fn get_ref(&self) -> &TcpStream {
@@ -152,18 +295,121 @@ pub mod client {
         pub fn into_inner(self) -> TcpStream {
             self.socket
         }
+
+        /// Creates a new independently owned handle to the underlying socket.
+        ///
+        /// # Notes
+        /// See `TcpStream::try_clone()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self {
+                socket: self.socket.try_clone()?,
+                proxy_addr: self.proxy_addr,
+                resolved_by: self.resolved_by,
+            })
+        }
+
+        /// Shuts down the read, write, or both halves of the connection.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.socket.shutdown(how)
+        }
+
+        /// Sets the read timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.socket.set_read_timeout(dur)
+        }
+
+        /// Sets the write timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.socket.set_write_timeout(dur)
+        }
+
+        /// Returns the read timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            self.socket.read_timeout()
+        }
+
+        /// Returns the write timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+            self.socket.write_timeout()
+        }
+
+        /// Sets the value of the `TCP_NODELAY` option on the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.socket.set_nodelay(nodelay)
+        }
+
+        /// Returns the value of the `TCP_NODELAY` option on the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn nodelay(&self) -> io::Result<bool> {
+            self.socket.nodelay()
+        }
+
+        /// Moves the underlying socket into or out of nonblocking mode.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.socket.set_nonblocking(nonblocking)
+        }
+
+        /// Returns the address of the proxy server this stream is connected to.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.peer_addr()
+        }
+
+        /// Returns the local address of the connection to the proxy server.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
     }
 
     impl Read for Socks4Stream {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             self.socket.read(buf)
         }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            self.socket.read_vectored(bufs)
+        }
     }
 
     impl Read for &Socks4Stream {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             (&self.socket).read(buf)
         }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            (&self.socket).read_vectored(bufs)
+        }
     }
 
     impl Write for Socks4Stream {
@@ -171,6 +417,10 @@ pub mod client {
             self.socket.write(buf)
         }
 
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.socket.write_vectored(bufs)
+        }
+
         fn flush(&mut self) -> io::Result<()> {
             self.socket.flush()
         }
@@ -181,27 +431,80 @@ pub mod client {
             (&self.socket).write(buf)
         }
 
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            (&self.socket).write_vectored(bufs)
+        }
+
         fn flush(&mut self) -> io::Result<()> {
             (&self.socket).flush()
         }
     }
+
+    impl From<Socks4Stream> for TcpStream {
+        fn from(stream: Socks4Stream) -> Self {
+            stream.socket
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsFd for Socks4Stream {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.socket.as_fd()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for Socks4Stream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.socket.as_raw_fd()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsSocket for Socks4Stream {
+        fn as_socket(&self) -> BorrowedSocket<'_> {
+            self.socket.as_socket()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsRawSocket for Socks4Stream {
+        fn as_raw_socket(&self) -> RawSocket {
+            self.socket.as_raw_socket()
+        }
+    }
 }
 
 #[cfg(feature = "bind")]
 pub mod bind {
     use crate::{
+        resolve,
         v4::{client::Socks4Stream, read_response},
+        AbortHandle, ConnectOptions, Error, ExpectedPeer, TargetAddr, ToResolvedAddrs,
         ToTargetAddr,
     };
-    use core::time::Duration;
+    use alloc::sync::Arc;
+    use core::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
     use std::{
-        io,
-        net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+        io::{self, Read},
+        net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream},
+        time::Instant,
     };
 
     /// A SOCKS4 and SOCKS4A BIND client.
     #[derive(Debug)]
-    pub struct Socks4Listener(Socks4Stream);
+    pub struct Socks4Listener {
+        // taken once a connection is accepted
+        stream: Option<Socks4Stream>,
+        proxy_addr: SocketAddr,
+        // the part of the second reply received so far
+        pending: Vec<u8>,
+        aborted: Arc<AtomicBool>,
+        resolve: resolve::Settings,
+    }
 
     impl Socks4Listener {
         /// Initiates a BIND request to the specified proxy.
@@ -221,10 +524,32 @@ pub mod bind {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
-            Socks4Stream::connect_raw(2, proxy, target, userid, connect_timeout).map(Socks4Listener)
+            Self::bind_with_options(proxy, target, userid, &connect_timeout.into())
+        }
+
+        /// Initiates a BIND request to the specified proxy with the given
+        /// `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::bind_with_options()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_options<T, U>(
+            proxy: T,
+            target: &U,
+            userid: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Socks4Stream::connect_raw(2, proxy, target, userid, options)
+                .and_then(|stream| Self::new(stream, options))
         }
 
         /// The address of the proxy-side TCP listener.
@@ -234,19 +559,30 @@ pub mod bind {
         ///
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
-        pub fn proxy_addr(&self) -> io::Result<SocketAddr> {
-            if self.0.proxy_addr.ip().octets() == [0, 0, 0, 0] {
-                let port = self.0.proxy_addr.port();
-                let peer = match self.0.socket.peer_addr()? {
+        pub const fn proxy_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.proxy_addr)
+        }
+
+        fn new(stream: Socks4Stream, options: &ConnectOptions) -> io::Result<Self> {
+            let proxy_addr = if stream.proxy_addr.ip().octets() == [0, 0, 0, 0] {
+                let port = stream.proxy_addr.port();
+                match stream.socket.peer_addr()? {
                     SocketAddr::V4(addr) => SocketAddr::V4(SocketAddrV4::new(*addr.ip(), port)),
                     SocketAddr::V6(addr) => {
                         SocketAddr::V6(SocketAddrV6::new(*addr.ip(), port, 0, 0))
                     }
-                };
-                Ok(peer)
+                }
             } else {
-                Ok(SocketAddr::V4(self.0.proxy_addr()))
-            }
+                SocketAddr::V4(stream.proxy_addr())
+            };
+
+            Ok(Self {
+                stream: Some(stream),
+                proxy_addr,
+                pending: vec![],
+                aborted: Arc::new(AtomicBool::new(false)),
+                resolve: options.resolve_settings().clone(),
+            })
         }
 
         /// Waits for the remote process to connect to the proxy server.
@@ -257,8 +593,290 @@ pub mod bind {
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
         pub fn accept(mut self) -> io::Result<Socks4Stream> {
-            self.0.proxy_addr = read_response(&mut self.0.socket)?;
-            Ok(self.0)
+            self.poll(None)
+        }
+
+        /// Like `accept`, but checks that the host that connected, as reported in
+        /// the second reply, is `expected`.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::accept_from()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::PermissionDenied, socks2::Error::UnexpectedBindPeer)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn accept_from(self, expected: &ExpectedPeer) -> io::Result<Socks4Stream> {
+            let resolve = self.resolve.clone();
+            let stream = self.accept()?;
+            let peer = TargetAddr::Ip(SocketAddr::V4(stream.proxy_addr()));
+            if expected.matches_with(&peer, &resolve)? {
+                Ok(stream)
+            } else {
+                Err(Error::UnexpectedBindPeer { peer }.into_io())
+            }
+        }
+
+        /// Like `accept`, but gives up after `timeout`.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::accept_timeout()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::TimedOut`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Socks4Stream> {
+            let socket = self.socket()?.try_clone()?;
+            let previous = socket.read_timeout()?;
+            let result = self.poll(Some(Instant::now() + timeout));
+            let restored = socket.set_read_timeout(previous);
+            match result {
+                // The stream is returned even if restoring the timeout failed.
+                Ok(stream) => Ok(stream),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    restored.and(Err(io::ErrorKind::TimedOut.into()))
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Like `accept`, but does not block.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::WouldBlock` until the remote process connected
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn try_accept(&mut self) -> io::Result<Socks4Stream> {
+            let socket = self.socket()?.try_clone()?;
+            socket.set_nonblocking(true)?;
+            let result = self.poll(None);
+            // The socket is not handed out before accepting and only made
+            // nonblocking here, so it was blocking before.
+            let restored = socket.set_nonblocking(false);
+            match result {
+                Ok(stream) => Ok(stream),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => restored.and(Err(err)),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Returns a handle that aborts waiting for the remote process from
+        /// another thread.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn abort_handle(&self) -> io::Result<AbortHandle> {
+            let socket = self.socket()?.try_clone()?;
+            Ok(AbortHandle::new(socket, Arc::clone(&self.aborted)))
+        }
+
+        fn socket(&self) -> io::Result<&TcpStream> {
+            self.stream
+                .as_ref()
+                .map(|stream| &stream.socket)
+                .ok_or_else(|| Error::ListenerAccepted {}.into_io())
+        }
+
+        // Reads the rest of the second reply, keeping what was read on errors.
+        // Before each read the socket times out at what is left until
+        // `deadline`.
+        fn poll(&mut self, deadline: Option<Instant>) -> io::Result<Socks4Stream> {
+            let result = self.fill(deadline);
+            if result.is_err() && self.aborted.load(Ordering::Acquire) {
+                return Err(Error::BindAborted {}.into_io());
+            }
+
+            let proxy_addr = result?;
+            let Some(mut stream) = self.stream.take() else {
+                return Err(Error::ListenerAccepted {}.into_io());
+            };
+            stream.proxy_addr = proxy_addr;
+            Ok(stream)
+        }
+
+        fn fill(&mut self, deadline: Option<Instant>) -> io::Result<SocketAddrV4> {
+            let Some(ref mut stream) = self.stream else {
+                return Err(Error::ListenerAccepted {}.into_io());
+            };
+
+            let mut buf = [0; 8];
+            while self.pending.len() < buf.len() {
+                if let Some(deadline) = deadline {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    stream.socket.set_read_timeout(Some(left))?;
+                }
+                match stream.socket.read(&mut buf[..8 - self.pending.len()]) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            read_response(&mut &self.pending[..])
+        }
+    }
+
+    /// Accepts many connections through a SOCKS4 proxy by issuing a new BIND
+    /// request, each on its own control connection, for every connection.
+    #[derive(Debug)]
+    pub struct Socks4AcceptLoop {
+        proxy: Vec<SocketAddr>,
+        target: TargetAddr,
+        userid: String,
+        options: ConnectOptions,
+        advertised: Option<SocketAddr>,
+        bind_ahead: bool,
+    }
+
+    impl Socks4AcceptLoop {
+        /// Creates an accept loop for the specified proxy. No request is sent
+        /// until `bind` or `incoming` is used.
+        ///
+        /// # Notes
+        /// See `Socks4Listener::bind()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn new<T, U>(
+            proxy: T,
+            target: &U,
+            userid: &str,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Self::with_options(proxy, target, userid, &connect_timeout.into())
+        }
+
+        /// Creates an accept loop for the specified proxy with the given
+        /// `ConnectOptions`, which every BIND request uses.
+        ///
+        /// # Notes
+        /// See `Socks4Listener::bind_with_options()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn with_options<T, U>(
+            proxy: T,
+            target: &U,
+            userid: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Ok(Self {
+                proxy: proxy.to_resolved_addrs(options.resolve_settings().resolver())?,
+                target: target.to_target_addr()?,
+                userid: userid.to_owned(),
+                options: options.clone(),
+                advertised: None,
+                bind_ahead: false,
+            })
+        }
+
+        /// Issues a new BIND request on a new control connection.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind(&self) -> io::Result<Socks4Listener> {
+            Socks4Stream::connect_raw(
+                2,
+                &self.proxy[..],
+                &self.target,
+                &self.userid,
+                &self.options,
+            )
+            .and_then(|stream| Socks4Listener::new(stream, &self.options))
+        }
+
+        /// Issues the next BIND request before waiting for the remote process in
+        /// `incoming`.
+        ///
+        /// # Notes
+        /// See `Socks5AcceptLoop::set_bind_ahead()`.
+        pub fn set_bind_ahead(&mut self, bind_ahead: bool) {
+            self.bind_ahead = bind_ahead;
+        }
+
+        /// Returns an iterator that issues a BIND request and waits for the
+        /// remote process for every connection.
+        ///
+        /// # Notes
+        /// See `Socks5AcceptLoop::incoming()`.
+        pub fn incoming<F>(&mut self, advertise: F) -> Socks4Incoming<'_, F>
+        where
+            F: FnMut(&SocketAddr) -> io::Result<()>,
+        {
+            Socks4Incoming {
+                ahead: self.bind_ahead,
+                accept_loop: self,
+                advertise,
+                next: None,
+            }
+        }
+
+        /// Returns the address last passed to `advertise`.
+        #[must_use]
+        pub const fn advertised(&self) -> Option<&SocketAddr> {
+            self.advertised.as_ref()
+        }
+    }
+
+    /// An iterator over the connections of a `Socks4AcceptLoop`, see
+    /// `Socks4AcceptLoop::incoming()`.
+    #[derive(Debug)]
+    pub struct Socks4Incoming<'a, F> {
+        accept_loop: &'a mut Socks4AcceptLoop,
+        advertise: F,
+        // cleared when falling back to one BIND at a time
+        ahead: bool,
+        next: Option<Socks4Listener>,
+    }
+
+    impl<F> Iterator for Socks4Incoming<'_, F>
+    where
+        F: FnMut(&SocketAddr) -> io::Result<()>,
+    {
+        type Item = io::Result<Socks4Stream>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let listener = match self.next.take() {
+                Some(listener) => listener,
+                None => match self.accept_loop.bind() {
+                    Ok(listener) => listener,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
+            let addr = match listener.proxy_addr() {
+                Ok(addr) => addr,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if self.accept_loop.advertised != Some(addr) {
+                if let Err(err) = (self.advertise)(&addr) {
+                    return Some(Err(err));
+                }
+                self.accept_loop.advertised = Some(addr);
+            }
+
+            if self.ahead {
+                match self.accept_loop.bind() {
+                    Ok(next) if next.proxy_addr().ok() != Some(addr) => self.next = Some(next),
+                    // See `Socks5Incoming`.
+                    _ => self.ahead = false,
+                }
+            }
+            Some(listener.accept())
         }
     }
 }
@@ -271,6 +889,7 @@ mod test {
     #[cfg(feature = "client")]
     use super::client::*;
 
+    use crate::{ext_bytes::BytesExt, mock, unwrap_io_to_socks2_error, ConnectOptions, Error};
     use core::time::Duration;
     use std::{
         io::{Read, Write},
@@ -279,6 +898,24 @@ mod test {
 
     const PROXY_ADDR: &str = "127.0.0.1:1084";
 
+    // Reads a SOCKS4 request, returning the IP and the SOCKS4A domain.
+    fn read_request(stream: &mut TcpStream, command: u8) -> ([u8; 4], Option<Vec<u8>>) {
+        let mut head = [0; 8];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[..2], [4, command]);
+        let read_str = |stream: &mut TcpStream| {
+            let mut out = vec![];
+            while let byte @ 1.. = stream.read_be_u8().unwrap() {
+                out.push(byte);
+            }
+            out
+        };
+        read_str(stream);
+        let ip = [head[4], head[5], head[6], head[7]];
+        let domain = (ip[..3] == [0, 0, 0] && ip[3] != 0).then(|| read_str(stream));
+        (ip, domain)
+    }
+
     fn google_ip() -> SocketAddrV4 {
         "google.com:80"
             .to_socket_addrs()
@@ -307,6 +944,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::ignore_without_reason)]
     #[ignore] // dante doesn't support SOCKS4A
     #[cfg(feature = "client")]
     fn google_dns() {
@@ -344,4 +982,194 @@ mod test {
         end.read_to_end(&mut result).unwrap();
         assert_eq!(result, b"hello world");
     }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_timeout() {
+        use std::{io, sync::mpsc};
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let (proxy, _) = mock::spawn(1, move |mut stream| {
+            read_request(&mut stream, 2);
+            stream
+                .write_all(&[0, 90, 0x0f, 0xa0, 127, 0, 0, 1])
+                .unwrap();
+            // the second reply is split across the timeouts
+            rx.recv().unwrap();
+            stream.write_all(&[0, 90, 0x13]).unwrap();
+            rx.recv().unwrap();
+            stream.write_all(&[0x88, 127, 0, 0, 1]).unwrap();
+            mock::drain(&mut stream);
+        });
+
+        let mut listener = Socks4Listener::bind(proxy, &"127.0.0.1:5000", "", None).unwrap();
+        let err = listener
+            .accept_timeout(Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            listener.try_accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        tx.send(()).unwrap();
+        let err = listener
+            .accept_timeout(Duration::from_millis(200))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        tx.send(()).unwrap();
+        let stream = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(stream.proxy_addr(), "127.0.0.1:5000".parse().unwrap());
+        // the timeout only applied while waiting
+        assert_eq!(stream.get_ref().read_timeout().unwrap(), None);
+        assert_eq!(
+            unwrap_io_to_socks2_error(&listener.try_accept().unwrap_err()),
+            Some(&Error::ListenerAccepted {})
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_loop() {
+        use alloc::{collections::VecDeque, sync::Arc};
+        use core::sync::atomic::{AtomicBool, Ordering};
+        use std::{sync::mpsc, thread};
+
+        for (ahead, reuse_port) in [(false, true), (true, true), (true, false)] {
+            // Like a real proxy, a BIND on a port that is still in use is refused.
+            let in_use = Arc::new(AtomicBool::new(false));
+            let listening = Arc::clone(&in_use);
+            let (tx, rx) = mpsc::channel();
+            let mut conn = 0;
+            let (proxy, _) = mock::spawn(
+                if reuse_port && !ahead { 3 } else { 4 },
+                move |mut stream| {
+                    read_request(&mut stream, 2);
+                    if reuse_port && listening.swap(true, Ordering::AcqRel) {
+                        stream.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).unwrap();
+                        return;
+                    }
+                    let port: u16 = if reuse_port { 4000 } else { 4000 + conn };
+                    conn += 1;
+                    let [hi, lo] = port.to_be_bytes();
+                    stream.write_all(&[0, 90, hi, lo, 127, 0, 0, 1]).unwrap();
+                    tx.send(stream).unwrap();
+                },
+            );
+
+            let accepter = thread::spawn(move || {
+                let mut accept_loop =
+                    Socks4AcceptLoop::new(proxy, &"127.0.0.1:80", "", None).unwrap();
+                accept_loop.set_bind_ahead(ahead);
+                let mut advertised = vec![];
+                let peers: Vec<SocketAddrV4> = accept_loop
+                    .incoming(|addr| {
+                        advertised.push(*addr);
+                        Ok(())
+                    })
+                    .take(3)
+                    .map(|stream| stream.unwrap().proxy_addr())
+                    .collect();
+                (advertised, peers)
+            });
+
+            let mut pending = VecDeque::new();
+            for i in 1..=3 {
+                if pending.is_empty() {
+                    pending.push_back(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+                }
+                if ahead && !reuse_port {
+                    // the next BIND is pending before the remote process connects
+                    pending.push_back(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+                } else {
+                    // one BIND at a time, the one ahead on the same port was refused
+                    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+                }
+                in_use.store(false, Ordering::Release);
+                let mut stream = pending.pop_front().unwrap();
+                stream
+                    .write_all(&[0, 90, 0x13, 0x88, 192, 0, 2, i])
+                    .unwrap();
+            }
+
+            let (advertised, peers) = accepter.join().unwrap();
+            let expected: &[u16] = if reuse_port {
+                &[4000]
+            } else {
+                &[4000, 4001, 4002]
+            };
+            assert_eq!(
+                advertised,
+                expected
+                    .iter()
+                    .map(|port| SocketAddr::from(([127, 0, 0, 1], *port)))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                peers,
+                [
+                    "192.0.2.1:5000".parse::<SocketAddrV4>().unwrap(),
+                    "192.0.2.2:5000".parse().unwrap(),
+                    "192.0.2.3:5000".parse().unwrap()
+                ]
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn resolution_fallback() {
+        // a proxy without SOCKS4A support
+        let (proxy, _) = mock::spawn(4, |mut stream| {
+            let (ip, domain) = read_request(&mut stream, 1);
+            if domain.is_some() {
+                stream.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).unwrap();
+            } else {
+                assert_eq!(ip, [192, 0, 2, 10]);
+                stream.write_all(&[0, 90, 0, 80, 192, 0, 2, 10]).unwrap();
+            }
+        });
+        let hosts = mock::Hosts::new(&[("socks4a.resolver.test", "192.0.2.10:0".parse().unwrap())]);
+
+        let options = ConnectOptions::new().resolver(Some(hosts.caching()));
+        let target = "socks4a.resolver.test:80";
+        let connect = |resolution| {
+            Socks4Stream::connect_with_resolution(proxy, &target, "", &options, resolution)
+        };
+
+        let err = connect(Socks4Resolution::Remote).unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::ConnectionRefused { code: 91 })
+        );
+
+        let stream = connect(Socks4Resolution::RemoteThenLocal).unwrap();
+        assert_eq!(stream.resolved_by(), Socks4ResolvedBy::Local);
+        assert_eq!(stream.proxy_addr(), "192.0.2.10:80".parse().unwrap());
+
+        let stream = connect(Socks4Resolution::Local).unwrap();
+        assert_eq!(stream.resolved_by(), Socks4ResolvedBy::Local);
+
+        // the resolution is cached by the resolver of the connection
+        assert_eq!(hosts.lookups("socks4a.resolver.test"), 1);
+
+        // refused before connecting, for this connection only
+        let protected = options.clone().dns_leak_protection(true);
+        let err = Socks4Stream::connect_with_resolution(
+            proxy,
+            &target,
+            "",
+            &protected,
+            Socks4Resolution::Local,
+        )
+        .unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::LocalDnsRefused {
+                domain: String::new()
+            })
+        );
+        assert!(!crate::dns_leak_protection());
+    }
 }
@@ -3,7 +3,7 @@ use std::{
     io::{
         Read, Write, {self},
     },
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
 const MAX_ADDR_LEN: usize = 260;
@@ -37,35 +37,63 @@ fn read_addr<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
     }
 }
 
-fn read_response(socket: &mut TcpStream) -> io::Result<TargetAddr> {
-    {
-        let version = socket.read_be_u8()?;
-        if version != 5 {
-            return Err(Error::InvalidResponseVersion { version }.into_io());
+fn read_response<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
+    // VER, REP, RSV, ATYP and the first address byte, which is enough to know
+    // the length of the rest of the reply without reading past it.
+    let mut response = [0; MAX_ADDR_LEN + 2];
+    let mut filled = 0;
+    while filled < 5 {
+        match socket.read(&mut response[filled..5]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
 
-    match socket.read_be_u8()? {
-        0 => {}
-        1 => return Err(Error::UnknownServerFailure { code: 1 }.into_io()),
-        2 => return Err(Error::ServerRefusedByRuleSet {}.into_io()),
-        3 => return Err(Error::ServerNetworkUnreachable {}.into_io()),
-        4 => return Err(Error::ServerHostUnreachable {}.into_io()),
-        5 => return Err(Error::ConnectionRefused { code: 5 }.into_io()),
-        6 => return Err(Error::ServerTTLExpired {}.into_io()),
-        7 => return Err(Error::ServerCmdNotSupported {}.into_io()),
-        8 => return Err(Error::ServerAddressNotSupported {}.into_io()),
-        code => return Err(Error::UnknownServerFailure { code }.into_io()),
+    // A short reply is still checked, as some servers close right after a failure.
+    if filled >= 1 && response[0] != 5 {
+        return Err(Error::InvalidResponseVersion {
+            version: response[0],
+        }
+        .into_io());
+    }
+
+    if filled >= 2 {
+        match response[1] {
+            0 => {}
+            1 => return Err(Error::UnknownServerFailure { code: 1 }.into_io()),
+            2 => return Err(Error::ServerRefusedByRuleSet {}.into_io()),
+            3 => return Err(Error::ServerNetworkUnreachable {}.into_io()),
+            4 => return Err(Error::ServerHostUnreachable {}.into_io()),
+            5 => return Err(Error::ConnectionRefused { code: 5 }.into_io()),
+            6 => return Err(Error::ServerTTLExpired {}.into_io()),
+            7 => return Err(Error::ServerCmdNotSupported {}.into_io()),
+            8 => return Err(Error::ServerAddressNotSupported {}.into_io()),
+            code => return Err(Error::UnknownServerFailure { code }.into_io()),
+        }
+    }
+
+    if filled < 5 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
     }
 
     {
-        let byte = socket.read_be_u8()?;
+        let byte = response[2];
         if byte != 0 {
             return Err(Error::InvalidReservedByte { byte }.into_io());
         }
     }
 
-    read_addr(socket)
+    let remaining = match response[3] {
+        1 => 4 - 1 + 2,
+        3 => response[4] as usize + 2,
+        4 => 16 - 1 + 2,
+        code => return Err(Error::SOCKS5InvalidAddressType { code }.into_io()),
+    };
+    socket.read_exact(&mut response[5..5 + remaining])?;
+
+    read_addr(&mut &response[3..5 + remaining])
 }
 
 fn write_addr(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
@@ -88,6 +116,7 @@ fn write_addr(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
                     .ok()
                     .and_then(|i| if i == 0 { None } else { Some(i) })
             else {
+                #[allow(clippy::implicit_clone)]
                 return Err(Error::InvalidDomainLength {
                     domain: domain.to_string(),
                     length: domain.len(),
@@ -124,20 +153,32 @@ impl Authentication<'_> {
     const fn is_no_auth(&self) -> bool {
         matches!(*self, Authentication::None)
     }
+
+    // The methods offered in the greeting, no auth is always offered.
+    const fn methods(&self) -> &'static [u8] {
+        match *self {
+            Authentication::Password { .. } => &[2, 0],
+            Authentication::None => &[0],
+        }
+    }
 }
 
 #[cfg(feature = "client")]
 pub mod client {
     use crate::{
-        tcp_stream_connect,
+        tcp_stream_connect, unwrap_io_to_socks2_error,
         v5::{read_response, write_addr, Authentication, MAX_ADDR_LEN},
-        Error, TargetAddr, ToTargetAddr,
+        ConnectOptions, Error, TargetAddr, ToResolvedAddrs, ToTargetAddr,
     };
     use core::time::Duration;
+    #[cfg(unix)]
+    use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+    #[cfg(windows)]
+    use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
     use std::{
         io,
-        io::{Read, Write},
-        net::{TcpStream, ToSocketAddrs},
+        io::{IoSlice, IoSliceMut, Read, Write},
+        net::{Shutdown, SocketAddr, TcpStream},
     };
 
     /// A SOCKS5 and SOCKS5H client.
@@ -166,10 +207,11 @@ pub mod client {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
-            Self::connect_raw(1, proxy, target, &Authentication::None, connect_timeout)
+            let options = connect_timeout.into();
+            Self::connect_raw(1, proxy, target, &Authentication::None, &options)
         }
 
         /// Connects to a target server through a SOCKS5 proxy using given
@@ -188,11 +230,165 @@ pub mod client {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let auth = Authentication::Password { username, password };
+            Self::connect_raw(1, proxy, target, &auth, &connect_timeout.into())
+        }
+
+        /// Connects to a target server through a SOCKS5 proxy with the given
+        /// `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()` and `ConnectOptions::fast_open()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_with_options<T, U>(
+            proxy: T,
+            target: &U,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Self::connect_raw(1, proxy, target, &Authentication::None, options)
+        }
+
+        /// Connects to a target server through a SOCKS5 proxy using given
+        /// username and password with the given `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect_with_options()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_with_password_and_options<T, U>(
+            proxy: T,
+            target: &U,
+            username: &str,
+            password: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let auth = Authentication::Password { username, password };
+            Self::connect_raw(1, proxy, target, &auth, options)
+        }
+
+        /// Connects to a target server through a SOCKS5 proxy, sending the
+        /// greeting, the CONNECT request and `early_data` in one flight.
+        ///
+        /// This saves a round trip by not waiting for the server to choose the
+        /// authentication method. `early_data` is application data that will be
+        /// sent to the target and may be empty.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_pipelined<T, U>(
+            proxy: T,
+            target: &U,
+            early_data: &[u8],
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let auth = Authentication::None;
+            let options = connect_timeout.into();
+            Self::connect_pipelined_raw(proxy, target, &auth, early_data, &options)
+        }
+
+        /// Connects to a target server through a SOCKS5 proxy using given
+        /// username and password, sending the greeting, the authentication, the
+        /// CONNECT request and `early_data` in one flight.
+        ///
+        /// # Notes
+        /// Only password authentication is offered in the pipelined greeting. If
+        /// the server chooses anything else, the connection is dropped and the
+        /// handshake is done again like `Socks5Stream::connect_with_password()`,
+        /// which also offers no authentication.
+        ///
+        /// See `Socks5Stream::connect_pipelined()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect_with_password_pipelined<T, U>(
+            proxy: T,
+            target: &U,
+            username: &str,
+            password: &str,
+            early_data: &[u8],
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
             let auth = Authentication::Password { username, password };
-            Self::connect_raw(1, proxy, target, &auth, connect_timeout)
+            let options = connect_timeout.into();
+            Self::connect_pipelined_raw(proxy, target, &auth, early_data, &options)
+        }
+
+        fn connect_pipelined_raw<T, U>(
+            proxy: T,
+            target: &U,
+            auth: &Authentication,
+            early_data: &[u8],
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            // Resolved once, in case the handshake has to be redone.
+            let proxy: Vec<SocketAddr> =
+                proxy.to_resolved_addrs(options.resolve_settings().resolver())?;
+            let target = target.to_target_addr()?;
+            let mut socket = tcp_stream_connect(&proxy[..], options)?;
+
+            // Only the expected method is offered, so a compliant server either
+            // chooses it or rejects the greeting.
+            let mut packet = vec![5, 1, auth.id()];
+            if let Authentication::Password { username, password } = *auth {
+                packet.extend(Self::password_request(username, password)?);
+            }
+            packet.extend(Self::request(1, &target)?);
+            packet.extend(early_data);
+            socket.write_all(&packet)?;
+
+            let mut buf = [0; 2];
+            socket.read_exact(&mut buf)?;
+            if buf[0] != 5 {
+                return Err(Error::InvalidResponseVersion { version: buf[0] }.into_io());
+            }
+
+            if buf[1] != auth.id() {
+                if buf[1] == 0xff && auth.is_no_auth() {
+                    return Err(Error::NoAuthMethods { method: buf[1] }.into_io());
+                }
+
+                // Whatever we pipelined after the greeting is now out of sync.
+                drop(socket);
+                let mut stream = Self::connect_raw(1, &proxy[..], &target, auth, options)?;
+                stream.socket.write_all(early_data)?;
+                return Ok(stream);
+            }
+
+            if !auth.is_no_auth() {
+                Self::password_response(&mut socket)?;
+            }
+            let proxy_addr = read_response(&mut socket)?;
+
+            Ok(Self { socket, proxy_addr })
         }
 
         pub(super) fn connect_raw<T, U>(
@@ -200,37 +396,99 @@ pub mod client {
             proxy: T,
             target: &U,
             auth: &Authentication,
-            connect_timeout: Option<Duration>,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let socket = tcp_stream_connect(proxy, options)?;
+            Self::handshake(socket, command, target, auth)
+        }
+
+        pub(super) fn handshake<U>(
+            mut socket: TcpStream,
+            command: u8,
+            target: &U,
+            auth: &Authentication,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
             U: ToTargetAddr,
         {
-            let mut socket = tcp_stream_connect(proxy, connect_timeout)?;
+            let target = target.to_target_addr()?;
+            let selected_method = Self::greet(&mut socket, auth.methods())?;
+            Self::negotiate(socket, selected_method, command, &target, auth)
+        }
 
+        // Connects like `connect_with_password_and_options`, but returns `None`
+        // if the proxy did not answer the greeting like a SOCKS5 proxy, so
+        // another protocol can be tried. Failures after the greeting are
+        // returned as they are.
+        pub(crate) fn connect_if_socks5<T, U>(
+            proxy: T,
+            target: &U,
+            credentials: Option<(&str, &str)>,
+            options: &ConnectOptions,
+        ) -> io::Result<Option<Self>>
+        where
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
             let target = target.to_target_addr()?;
+            let auth = match credentials {
+                Some((username, password)) => Authentication::Password { username, password },
+                None => Authentication::None,
+            };
+
+            let mut socket = tcp_stream_connect(proxy, options)?;
+            match Self::greet(&mut socket, auth.methods()) {
+                Ok(selected_method) => {
+                    Self::negotiate(socket, selected_method, 1, &target, &auth).map(Some)
+                }
+                Err(err)
+                    if matches!(
+                        unwrap_io_to_socks2_error(&err),
+                        Some(Error::InvalidResponseVersion { .. })
+                    ) || matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    Ok(None)
+                }
+                Err(err) => Err(err),
+            }
+        }
 
-            let packet_len = if auth.is_no_auth() { 3 } else { 4 };
-            let packet = [
-                5,                                     // protocol version
-                if auth.is_no_auth() { 1 } else { 2 }, // method count
-                auth.id(),                             // method
-                0,                                     // no auth (always offered)
+        // Offers `methods` and returns the one the proxy selected, 0xff if it
+        // accepts none of them.
+        pub(super) fn greet(socket: &mut TcpStream, methods: &[u8]) -> io::Result<u8> {
+            let count = u8::try_from(methods.len()).unwrap_or(u8::MAX);
+            let mut packet = vec![
+                5,     // protocol version
+                count, // method count
             ];
-            socket.write_all(&packet[..packet_len])?;
+            packet.extend(&methods[..usize::from(count)]);
+            socket.write_all(&packet)?;
 
             let mut buf = [0; 2];
             socket.read_exact(&mut buf)?;
-            let response_version = buf[0];
-            let selected_method = buf[1];
-
-            if response_version != 5 {
-                return Err(Error::InvalidResponseVersion {
-                    version: response_version,
-                }
-                .into_io());
+            if buf[0] != 5 {
+                return Err(Error::InvalidResponseVersion { version: buf[0] }.into_io());
             }
+            Ok(buf[1])
+        }
 
+        // The rest of the handshake, after the proxy selected a method.
+        fn negotiate(
+            mut socket: TcpStream,
+            selected_method: u8,
+            command: u8,
+            target: &TargetAddr,
+            auth: &Authentication,
+        ) -> io::Result<Self> {
             if selected_method == 0xff {
                 return Err(Error::NoAuthMethods {
                     method: selected_method,
@@ -252,23 +510,32 @@ pub mod client {
                 _ => (),
             }
 
-            let mut packet = [0; MAX_ADDR_LEN + 3];
-            packet[0] = 5; // protocol version
-            packet[1] = command; // command
-            packet[2] = 0; // reserved
-            let len = write_addr(&mut packet[3..], &target)?;
-            socket.write_all(&packet[..len + 3])?;
+            socket.write_all(&Self::request(command, target)?)?;
 
             let proxy_addr = read_response(&mut socket)?;
 
             Ok(Self { socket, proxy_addr })
         }
 
+        fn request(command: u8, target: &TargetAddr) -> io::Result<Vec<u8>> {
+            let mut packet = [0; MAX_ADDR_LEN + 3];
+            packet[0] = 5; // protocol version
+            packet[1] = command; // command
+            packet[2] = 0; // reserved
+            let len = write_addr(&mut packet[3..], target)?;
+            Ok(packet[..len + 3].to_vec())
+        }
+
         fn password_authentication(
             socket: &mut TcpStream,
             username: &str,
             password: &str,
         ) -> io::Result<()> {
+            socket.write_all(&Self::password_request(username, password)?)?;
+            Self::password_response(socket)
+        }
+
+        fn password_request(username: &str, password: &str) -> io::Result<Vec<u8>> {
             let Some(username_len) =
                 u8::try_from(username.len())
                     .ok()
@@ -300,8 +567,10 @@ pub mod client {
             packet[2..2 + username.len()].copy_from_slice(username.as_bytes());
             packet[2 + username.len()] = password_len;
             packet[3 + username.len()..packet_size].copy_from_slice(password.as_bytes());
-            socket.write_all(&packet[..packet_size])?;
+            Ok(packet[..packet_size].to_vec())
+        }
 
+        fn password_response(socket: &mut TcpStream) -> io::Result<()> {
             let mut buf = [0; 2];
             socket.read_exact(&mut buf)?;
             if buf[0] != 1 {
@@ -337,18 +606,120 @@ pub mod client {
         pub fn into_inner(self) -> TcpStream {
             self.socket
         }
+
+        /// Creates a new independently owned handle to the underlying socket.
+        ///
+        /// # Notes
+        /// See `TcpStream::try_clone()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self {
+                socket: self.socket.try_clone()?,
+                proxy_addr: self.proxy_addr.clone(),
+            })
+        }
+
+        /// Shuts down the read, write, or both halves of the connection.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.socket.shutdown(how)
+        }
+
+        /// Sets the read timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.socket.set_read_timeout(dur)
+        }
+
+        /// Sets the write timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.socket.set_write_timeout(dur)
+        }
+
+        /// Returns the read timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            self.socket.read_timeout()
+        }
+
+        /// Returns the write timeout of the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+            self.socket.write_timeout()
+        }
+
+        /// Sets the value of the `TCP_NODELAY` option on the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.socket.set_nodelay(nodelay)
+        }
+
+        /// Returns the value of the `TCP_NODELAY` option on the underlying socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn nodelay(&self) -> io::Result<bool> {
+            self.socket.nodelay()
+        }
+
+        /// Moves the underlying socket into or out of nonblocking mode.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.socket.set_nonblocking(nonblocking)
+        }
+
+        /// Returns the address of the proxy server this stream is connected to.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.peer_addr()
+        }
+
+        /// Returns the local address of the connection to the proxy server.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
     }
 
     impl Read for Socks5Stream {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             self.socket.read(buf)
         }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            self.socket.read_vectored(bufs)
+        }
     }
 
     impl Read for &Socks5Stream {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             (&self.socket).read(buf)
         }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            (&self.socket).read_vectored(bufs)
+        }
     }
 
     impl Write for Socks5Stream {
@@ -356,6 +727,10 @@ pub mod client {
             self.socket.write(buf)
         }
 
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.socket.write_vectored(bufs)
+        }
+
         fn flush(&mut self) -> io::Result<()> {
             self.socket.flush()
         }
@@ -366,24 +741,90 @@ pub mod client {
             (&self.socket).write(buf)
         }
 
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            (&self.socket).write_vectored(bufs)
+        }
+
         fn flush(&mut self) -> io::Result<()> {
             (&self.socket).flush()
         }
     }
+
+    impl From<Socks5Stream> for TcpStream {
+        fn from(stream: Socks5Stream) -> Self {
+            stream.socket
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsFd for Socks5Stream {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.socket.as_fd()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for Socks5Stream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.socket.as_raw_fd()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsSocket for Socks5Stream {
+        fn as_socket(&self) -> BorrowedSocket<'_> {
+            self.socket.as_socket()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsRawSocket for Socks5Stream {
+        fn as_raw_socket(&self) -> RawSocket {
+            self.socket.as_raw_socket()
+        }
+    }
 }
 
 #[cfg(feature = "bind")]
 pub mod bind {
     use crate::{
-        v5::{read_response, Authentication},
-        Socks5Stream, TargetAddr, ToTargetAddr,
+        resolve,
+        v5::{read_response, Authentication, MAX_ADDR_LEN},
+        AbortHandle, ConnectOptions, Error, ExpectedPeer, Socks5Stream, TargetAddr,
+        ToResolvedAddrs, ToTargetAddr,
+    };
+    use alloc::sync::Arc;
+    use core::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use std::{
+        io::{self, Read},
+        net::{SocketAddr, TcpStream},
+        time::Instant,
     };
-    use core::time::Duration;
-    use std::{io, net::ToSocketAddrs};
 
     /// A SOCKS5 and SOCKS5H BIND client.
     #[derive(Debug)]
-    pub struct Socks5Listener(Socks5Stream);
+    pub struct Socks5Listener {
+        // taken once a connection is accepted
+        stream: Option<Socks5Stream>,
+        proxy_addr: TargetAddr,
+        // the part of the second reply received so far
+        pending: Vec<u8>,
+        aborted: Arc<AtomicBool>,
+        resolve: resolve::Settings,
+    }
+
+    // The length of the reply starting with `head`, as far as it is known.
+    fn response_len(head: &[u8]) -> usize {
+        match head.get(3..5) {
+            Some(&[1, _]) => 10,
+            Some(&[3, len]) => 7 + len as usize,
+            Some(&[4, _]) => 22,
+            _ => 5,
+        }
+    }
 
     impl Socks5Listener {
         /// Initiates a BIND request to the specified proxy.
@@ -402,11 +843,32 @@ pub mod bind {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            Self::bind_with_options(proxy, target, &connect_timeout.into())
+        }
+
+        /// Initiates a BIND request to the specified proxy with the given
+        /// `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::bind()`. The `Resolver` of `options` is also
+        /// used by `advertised_socket_addr` and `accept_from`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_options<T, U>(
+            proxy: T,
+            target: &U,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
-            Socks5Stream::connect_raw(2, proxy, target, &Authentication::None, connect_timeout)
-                .map(Socks5Listener)
+            Socks5Stream::connect_raw(2, proxy, target, &Authentication::None, options)
+                .and_then(|stream| Self::new(stream, options))
         }
         /// Initiates a BIND request to the specified proxy using given username
         /// and password.
@@ -427,20 +889,82 @@ pub mod bind {
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
+        {
+            let options = connect_timeout.into();
+            Self::bind_with_password_and_options(proxy, target, username, password, &options)
+        }
+
+        /// Initiates a BIND request to the specified proxy using given username
+        /// and password with the given `ConnectOptions`.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::bind_with_options()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_password_and_options<T, U>(
+            proxy: T,
+            target: &U,
+            username: &str,
+            password: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
             U: ToTargetAddr,
         {
             let auth = Authentication::Password { username, password };
-            Socks5Stream::connect_raw(2, proxy, target, &auth, connect_timeout).map(Socks5Listener)
+            Socks5Stream::connect_raw(2, proxy, target, &auth, options)
+                .and_then(|stream| Self::new(stream, options))
         }
 
         /// The address of the proxy-side TCP listener.
         ///
         /// This should be forwarded to the remote process, which should open a
         /// connection to it.
-        #[must_use]
+        ///
+        /// # Notes
+        /// An unspecified IPv4 or IPv6 address in the reply is replaced with the
+        /// IP of the proxy.
+        #[must_use]
         pub const fn proxy_addr(&self) -> &TargetAddr {
-            &self.0.proxy_addr
+            &self.proxy_addr
+        }
+
+        /// The address of the proxy-side TCP listener, resolving it if the proxy
+        /// replied with a domain name.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn advertised_socket_addr(&self) -> io::Result<SocketAddr> {
+            let addrs = match self.proxy_addr {
+                TargetAddr::Ip(addr) => vec![addr],
+                TargetAddr::Domain(ref domain, port) => self.resolve.resolve(domain, port)?,
+            };
+            addrs
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::NoResolveSocketAddrs {}.into_io())
+        }
+
+        fn new(stream: Socks5Stream, options: &ConnectOptions) -> io::Result<Self> {
+            // the listener is on the host we are talking to over TCP.
+            let proxy_addr = match stream.proxy_addr {
+                TargetAddr::Ip(addr) if addr.ip().is_unspecified() => TargetAddr::Ip(
+                    SocketAddr::new(stream.socket.peer_addr()?.ip(), addr.port()),
+                ),
+                ref addr => addr.clone(),
+            };
+
+            Ok(Self {
+                stream: Some(stream),
+                proxy_addr,
+                pending: vec![],
+                aborted: Arc::new(AtomicBool::new(false)),
+                resolve: options.resolve_settings().clone(),
+            })
         }
 
         /// Waits for the remote process to connect to the proxy server.
@@ -451,382 +975,4211 @@ pub mod bind {
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
         pub fn accept(mut self) -> io::Result<Socks5Stream> {
-            self.0.proxy_addr = read_response(&mut self.0.socket)?;
-            Ok(self.0)
+            self.poll(None)
         }
-    }
-}
 
-#[cfg(feature = "udp")]
-pub mod udp {
-    use crate::{
-        ext_bytes::BytesExt,
-        ext_io::IOVecExt,
-        v5::{read_addr, write_addr, Authentication, MAX_ADDR_LEN},
-        Error, Socks5Stream, TargetAddr, ToTargetAddr,
-    };
-    use core::{cmp, ptr, time::Duration};
-    use std::{
-        io,
-        net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
-    };
+        /// Like `accept`, but checks that the host that connected, as reported in
+        /// the second reply, is `expected`.
+        ///
+        /// # Notes
+        /// The stream is closed if another host connected.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::PermissionDenied, socks2::Error::UnexpectedBindPeer)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn accept_from(self, expected: &ExpectedPeer) -> io::Result<Socks5Stream> {
+            let resolve = self.resolve.clone();
+            let stream = self.accept()?;
+            if expected.matches_with(stream.proxy_addr(), &resolve)? {
+                Ok(stream)
+            } else {
+                Err(Error::UnexpectedBindPeer {
+                    peer: stream.proxy_addr().clone(),
+                }
+                .into_io())
+            }
+        }
 
-    /// A SOCKS5 and SOCKS5H UDP client.
+        /// Like `accept`, but gives up after `timeout`.
+        ///
+        /// On a timeout the listener can be used to wait again, a partly
+        /// received reply is kept.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::TimedOut`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Socks5Stream> {
+            let socket = self.socket()?.try_clone()?;
+            let previous = socket.read_timeout()?;
+            let result = self.poll(Some(Instant::now() + timeout));
+            let restored = socket.set_read_timeout(previous);
+            match result {
+                // The stream is returned even if restoring the timeout failed.
+                Ok(stream) => Ok(stream),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    restored.and(Err(io::ErrorKind::TimedOut.into()))
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Like `accept`, but does not block.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::WouldBlock` until the remote process connected
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn try_accept(&mut self) -> io::Result<Socks5Stream> {
+            let socket = self.socket()?.try_clone()?;
+            socket.set_nonblocking(true)?;
+            let result = self.poll(None);
+            // The socket is not handed out before accepting and only made
+            // nonblocking here, so it was blocking before.
+            let restored = socket.set_nonblocking(false);
+            match result {
+                Ok(stream) => Ok(stream),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => restored.and(Err(err)),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Returns a handle that aborts waiting for the remote process from
+        /// another thread.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn abort_handle(&self) -> io::Result<AbortHandle> {
+            let socket = self.socket()?.try_clone()?;
+            Ok(AbortHandle::new(socket, Arc::clone(&self.aborted)))
+        }
+
+        fn socket(&self) -> io::Result<&TcpStream> {
+            self.stream
+                .as_ref()
+                .map(|stream| &stream.socket)
+                .ok_or_else(|| Error::ListenerAccepted {}.into_io())
+        }
+
+        // Reads the rest of the second reply, keeping what was read on errors.
+        // Before each read the socket times out at what is left until
+        // `deadline`.
+        fn poll(&mut self, deadline: Option<Instant>) -> io::Result<Socks5Stream> {
+            let result = self.fill(deadline);
+            if result.is_err() && self.aborted.load(Ordering::Acquire) {
+                return Err(Error::BindAborted {}.into_io());
+            }
+
+            let proxy_addr = result?;
+            let Some(mut stream) = self.stream.take() else {
+                return Err(Error::ListenerAccepted {}.into_io());
+            };
+            stream.proxy_addr = proxy_addr;
+            Ok(stream)
+        }
+
+        fn fill(&mut self, deadline: Option<Instant>) -> io::Result<TargetAddr> {
+            let Some(ref mut stream) = self.stream else {
+                return Err(Error::ListenerAccepted {}.into_io());
+            };
+
+            let mut buf = [0; MAX_ADDR_LEN + 2];
+            loop {
+                let needed = response_len(&self.pending);
+                if self.pending.len() >= needed {
+                    return read_response(&mut &self.pending[..]);
+                }
+
+                if let Some(deadline) = deadline {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    stream.socket.set_read_timeout(Some(left))?;
+                }
+                match stream.socket.read(&mut buf[..needed - self.pending.len()]) {
+                    // Reports a short failure reply, or UnexpectedEof.
+                    Ok(0) => return read_response(&mut &self.pending[..]),
+                    Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+
+    /// Accepts many connections through a SOCKS5 proxy by issuing a new BIND
+    /// request, each on its own control connection, for every connection.
     #[derive(Debug)]
-    pub struct Socks5Datagram {
-        socket: UdpSocket,
-        // keeps the session alive
-        stream: Socks5Stream,
+    pub struct Socks5AcceptLoop {
+        proxy: Vec<SocketAddr>,
+        target: TargetAddr,
+        credentials: Option<(String, String)>,
+        options: ConnectOptions,
+        advertised: Option<TargetAddr>,
+        bind_ahead: bool,
     }
 
-    impl Socks5Datagram {
-        /// Creates a UDP socket bound to the specified address which will have its
-        /// traffic routed through the specified proxy.
+    impl Socks5AcceptLoop {
+        /// Creates an accept loop for the specified proxy. No request is sent
+        /// until `bind` or `incoming` is used.
         ///
         /// # Notes
-        /// See `Socks5Stream::connect()`.
+        /// See `Socks5Listener::bind()`.
         ///
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
-        pub fn bind<T, U>(proxy: T, addr: U, connect_timeout: Option<Duration>) -> io::Result<Self>
+        pub fn new<T, U>(
+            proxy: T,
+            target: &U,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
-            U: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
         {
-            Self::bind_internal(proxy, addr, &Authentication::None, connect_timeout)
+            Self::with_options(proxy, target, &connect_timeout.into())
         }
 
-        /// Creates a UDP socket bound to the specified address which will have its
-        /// traffic routed through the specified proxy. The given username and password
-        /// is used to authenticate to the SOCKS proxy.
+        /// Creates an accept loop for the specified proxy with the given
+        /// `ConnectOptions`, which every BIND request uses.
         ///
         /// # Notes
-        /// See `Socks5Stream::connect()`.
+        /// See `Socks5Listener::bind_with_options()`.
         ///
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
-        pub fn bind_with_password<T, U>(
+        pub fn with_options<T, U>(
             proxy: T,
-            addr: U,
-            username: &str,
-            password: &str,
-            connect_timeout: Option<Duration>,
+            target: &U,
+            options: &ConnectOptions,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
-            U: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
         {
-            let auth = Authentication::Password { username, password };
-            Self::bind_internal(proxy, addr, &auth, connect_timeout)
+            Ok(Self {
+                proxy: proxy.to_resolved_addrs(options.resolve_settings().resolver())?,
+                target: target.to_target_addr()?,
+                credentials: None,
+                options: options.clone(),
+                advertised: None,
+                bind_ahead: false,
+            })
         }
 
-        fn bind_internal<T, U>(
+        /// Creates an accept loop for the specified proxy using given username
+        /// and password.
+        ///
+        /// # Notes
+        /// See `Socks5Listener::bind_with_password()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn with_password<T, U>(
             proxy: T,
-            addr: U,
-            auth: &Authentication,
+            target: &U,
+            username: &str,
+            password: &str,
             connect_timeout: Option<Duration>,
         ) -> io::Result<Self>
         where
-            T: ToSocketAddrs,
-            U: ToSocketAddrs,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
         {
-            // we don't know what our IP is from the perspective of the proxy, so
-            // don't try to pass `addr` in here.
-            let dst = TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
-                Ipv4Addr::new(0, 0, 0, 0),
-                0,
-            )));
-            let stream = Socks5Stream::connect_raw(3, proxy, &dst, auth, connect_timeout)?;
-
-            let socket = UdpSocket::bind(addr)?;
-            socket.connect(&stream.proxy_addr)?;
-
-            Ok(Self { socket, stream })
+            let options = connect_timeout.into();
+            Self::with_password_and_options(proxy, target, username, password, &options)
         }
 
-        /// Like `UdpSocket::send_to`.
+        /// Creates an accept loop for the specified proxy using given username
+        /// and password with the given `ConnectOptions`.
         ///
-        /// # Note
-        /// The SOCKS protocol inserts a header at the beginning of the message. The
-        /// header will be 10 bytes for an IPv4 address, 22 bytes for an IPv6
-        /// address, and 7 bytes plus the length of the domain for a domain address.
+        /// # Notes
+        /// See `Socks5AcceptLoop::with_options()`.
         ///
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
-        pub fn send_to<A>(&self, buf: &[u8], addr: &A) -> io::Result<usize>
+        pub fn with_password_and_options<T, U>(
+            proxy: T,
+            target: &U,
+            username: &str,
+            password: &str,
+            options: &ConnectOptions,
+        ) -> io::Result<Self>
         where
-            A: ToTargetAddr,
+            T: ToResolvedAddrs,
+            U: ToTargetAddr,
         {
-            let addr = addr.to_target_addr()?;
-
-            let mut header = [0; MAX_ADDR_LEN + 3];
-            // first two bytes are reserved at 0
-            // third byte is the fragment id at 0
-            let len = write_addr(&mut header[3..], &addr)?;
-
-            // TODO: Use write_vectored?
-            self.socket.writev([&header[..len + 3], buf])
+            let mut accept_loop = Self::with_options(proxy, target, options)?;
+            accept_loop.credentials = Some((username.to_owned(), password.to_owned()));
+            Ok(accept_loop)
         }
 
-        /// Like `UdpSocket::recv_from`.
+        /// Issues a new BIND request on a new control connection.
         ///
         /// # Errors
         /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
-        pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
-            let mut header = [0; MAX_ADDR_LEN + 3];
-            // TODO: Use read_vectored?
-            let len = self.socket.readv([&mut header, buf])?;
-
-            let overflow = len.saturating_sub(header.len());
-
-            let header_len = cmp::min(header.len(), len);
-            let mut header = &mut &header[..header_len];
-
-            {
-                let bytes = header.read_be_u16()?;
-                if bytes != 0 {
-                    return Err(Error::InvalidReservedBytes { bytes }.into());
-                }
-            }
-            {
-                let fid = header.read_be_u8()?;
-                if fid != 0 {
-                    return Err(Error::InvalidFragmentID { fid }.into_io());
+        pub fn bind(&self) -> io::Result<Socks5Listener> {
+            let auth = match self.credentials {
+                Some((ref username, ref password)) => {
+                    Authentication::Password { username, password }
                 }
-            }
-            let addr = read_addr(&mut header)?;
-
-            unsafe {
-                ptr::copy(buf.as_ptr(), buf.as_mut_ptr().add(header.len()), overflow);
-            }
-            buf[..header.len()].copy_from_slice(header);
+                None => Authentication::None,
+            };
+            Socks5Stream::connect_raw(2, &self.proxy[..], &self.target, &auth, &self.options)
+                .and_then(|stream| Socks5Listener::new(stream, &self.options))
+        }
 
-            Ok((header.len() + overflow, addr))
+        /// Issues the next BIND request before waiting for the remote process in
+        /// `incoming`, so one is pending ahead of the one being accepted and the
+        /// remote process can connect again right away. Off by default.
+        ///
+        /// # Notes
+        /// A proxy that keeps the same port across BINDs cannot listen on it
+        /// twice. If the BIND ahead fails or reports the address of the listener
+        /// being accepted, it is dropped and the iterator falls back to one BIND
+        /// at a time.
+        pub fn set_bind_ahead(&mut self, bind_ahead: bool) {
+            self.bind_ahead = bind_ahead;
         }
 
-        /// Returns the address of the proxy-side UDP socket through which all
-        /// messages will be routed.
-        #[must_use]
-        pub const fn proxy_addr(&self) -> &TargetAddr {
-            &self.stream.proxy_addr
+        /// Returns an iterator that issues a BIND request and waits for the
+        /// remote process for every connection.
+        ///
+        /// `advertise` is called with the address of each new proxy-side
+        /// listener, and should forward it to the remote process. Proxies often
+        /// reuse the same port for the next BIND, in which case `advertise` is
+        /// not called again and the remote process can keep connecting to the
+        /// address it already has. An error from `advertise` is returned by the
+        /// iterator.
+        ///
+        /// The next BIND request is issued once a connection was accepted, see
+        /// `set_bind_ahead()` to issue it earlier.
+        ///
+        /// The iterator never returns `None`.
+        pub fn incoming<F>(&mut self, advertise: F) -> Socks5Incoming<'_, F>
+        where
+            F: FnMut(&TargetAddr) -> io::Result<()>,
+        {
+            Socks5Incoming {
+                ahead: self.bind_ahead,
+                accept_loop: self,
+                advertise,
+                next: None,
+            }
         }
 
-        /// Returns a shared reference to the inner socket.
+        /// Returns the address last passed to `advertise`.
         #[must_use]
-        pub const fn get_ref(&self) -> &UdpSocket {
-            &self.socket
+        pub const fn advertised(&self) -> Option<&TargetAddr> {
+            self.advertised.as_ref()
         }
+    }
 
-        /// Returns a mutable reference to the inner socket.
-        pub fn get_mut(&mut self) -> &mut UdpSocket {
-            &mut self.socket
+    /// An iterator over the connections of a `Socks5AcceptLoop`, see
+    /// `Socks5AcceptLoop::incoming()`.
+    #[derive(Debug)]
+    pub struct Socks5Incoming<'a, F> {
+        accept_loop: &'a mut Socks5AcceptLoop,
+        advertise: F,
+        // cleared when falling back to one BIND at a time
+        ahead: bool,
+        next: Option<Socks5Listener>,
+    }
+
+    impl<F> Iterator for Socks5Incoming<'_, F>
+    where
+        F: FnMut(&TargetAddr) -> io::Result<()>,
+    {
+        type Item = io::Result<Socks5Stream>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let listener = match self.next.take() {
+                Some(listener) => listener,
+                None => match self.accept_loop.bind() {
+                    Ok(listener) => listener,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
+
+            let addr = listener.proxy_addr();
+            if self.accept_loop.advertised.as_ref() != Some(addr) {
+                if let Err(err) = (self.advertise)(addr) {
+                    return Some(Err(err));
+                }
+                self.accept_loop.advertised = Some(addr.clone());
+            }
+
+            if self.ahead {
+                match self.accept_loop.bind() {
+                    Ok(next) if next.proxy_addr() != addr => self.next = Some(next),
+                    // The proxy refused to listen on the port twice, or may take
+                    // it from the listener below.
+                    _ => self.ahead = false,
+                }
+            }
+            Some(listener.accept())
         }
     }
 }
 
-#[cfg(test)]
-#[allow(clippy::unwrap_used)]
-mod test {
-    #[cfg(feature = "bind")]
-    use super::bind::*;
-    #[cfg(feature = "client")]
-    use super::client::*;
-    #[cfg(feature = "udp")]
-    use super::udp::*;
-
-    use super::*;
-    use crate::unwrap_io_to_socks2_error;
-    use core::time::Duration;
+#[cfg(feature = "udp")]
+pub mod udp {
+    #[cfg(target_os = "linux")]
+    use crate::ext_io::{MMsgBuffers, MMsgExt};
+    use crate::{
+        ext_bytes::BytesExt,
+        ext_io::IOVecExt,
+        is_io_socks2_error, resolve, tcp_stream_connect, unwrap_io_to_socks2_error,
+        v5::{read_addr, write_addr, Authentication, MAX_ADDR_LEN},
+        Error, Resolver, Socks5Stream, TargetAddr, ToResolvedAddrs, ToTargetAddr,
+    };
+    use alloc::sync::Arc;
+    use core::{
+        cmp, mem,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    #[cfg(unix)]
+    use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+    #[cfg(windows)]
+    use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
     use std::{
-        io::{Read, Write},
-        net::{TcpStream, ToSocketAddrs, UdpSocket},
+        io::{self, Read, Write},
+        net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
+        sync::{Mutex, PoisonError, RwLock, RwLockReadGuard},
+        thread,
+        time::Instant,
     };
 
-    const SOCKS_PROXY_NO_AUTH_ONLY: &str = "127.0.0.1:1084";
-    const SOCKS_PROXY_PASSWD_ONLY: &str = "127.0.0.1:1085";
+    /// The smallest reassembly timer allowed by RFC 1928.
+    pub const MIN_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
 
-    #[test]
-    #[cfg(feature = "client")]
-    fn google_no_auth() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let socket = Socks5Stream::connect(
-            SOCKS_PROXY_NO_AUTH_ONLY,
-            &addr,
-            Some(Duration::from_secs(25)),
-        )
-        .unwrap();
-        google(socket);
-    }
+    // Datagrams are received into a head of this size, the header for an IPv4
+    // address, then the caller's buffer, so that the payload of most replies
+    // lands at the start of the buffer. The payload pushed out of the buffer by a
+    // longer header goes in the tail.
+    const HEAD_LEN: usize = 10;
+    const TAIL_LEN: usize = MAX_ADDR_LEN + 3 - HEAD_LEN;
 
-    #[test]
-    #[cfg(feature = "client")]
-    fn google_with_password() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let socket = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            &addr,
-            "testuser",
-            "testpass",
-            None,
-        )
-        .unwrap();
-        google(socket);
-    }
+    // FRAG values 1-127 are positions, the high bit marks the last fragment.
+    const MAX_FRAGMENTS: u8 = 0x7f;
+    const END_OF_FRAGMENTS: u8 = 0x80;
 
-    #[cfg(feature = "client")]
-    fn google(mut socket: Socks5Stream) {
-        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
-        let mut result = vec![];
-        socket.read_to_end(&mut result).unwrap();
+    // Address types of UDP over TCP packets.
+    const UOT_IPV4: u8 = 0;
+    const UOT_IPV6: u8 = 1;
+    const UOT_DOMAIN: u8 = 2;
 
-        println!("{}", String::from_utf8_lossy(&result));
-        assert!(result.starts_with(b"HTTP/1.0"));
-        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    /// A SOCKS5 and SOCKS5H UDP client.
+    ///
+    /// # Notes
+    /// Unless tunneling over TCP, a background thread watches the control
+    /// connection, so a lost association is noticed without sending or
+    /// receiving, and a receive blocked at that moment returns.
+    #[derive(Debug)]
+    pub struct Socks5Datagram {
+        socket: UdpSocket,
+        // of the first association
+        proxy_addr: TargetAddr,
+        relay_addrs: Vec<SocketAddr>,
+        // shared with clones, replaced when reassociating
+        association: Arc<RwLock<Arc<Association>>>,
+        reassociate: Option<Arc<Reassociate>>,
+        transport: Transport,
+        reassembly: Option<Arc<Mutex<Reassembly>>>,
+        fragment_size: Option<usize>,
+        // default destination and its encoded header
+        peer: Option<(TargetAddr, Vec<u8>)>,
+        // not shared with clones, so they can receive batches at the same time
+        #[cfg(target_os = "linux")]
+        batch: Mutex<RecvBatch>,
     }
 
-    #[test]
-    #[cfg(feature = "client")]
-    fn google_dns() {
-        let mut socket =
-            Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, &"google.com:80", None).unwrap();
-
-        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
-        let mut result = vec![];
-        socket.read_to_end(&mut result).unwrap();
+    /// The buffers around the caller's for `recv_many`, reused between calls.
+    #[cfg(target_os = "linux")]
+    #[derive(Debug, Default)]
+    struct RecvBatch {
+        heads: Vec<[u8; HEAD_LEN]>,
+        tails: Vec<[u8; TAIL_LEN]>,
+        lens: Vec<usize>,
+        mmsg: MMsgBuffers,
+    }
 
-        println!("{}", String::from_utf8_lossy(&result));
-        assert!(result.starts_with(b"HTTP/1.0"));
-        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    /// The control connection and the relay it set up.
+    #[derive(Debug)]
+    struct Association {
+        // keeps the session alive
+        stream: Socks5Stream,
+        relay_addrs: Vec<SocketAddr>,
+        lost: AtomicBool,
+        // When tunneling, serializes reads and keeps a frame that a read timeout
+        // cut short, so the stream stays in sync.
+        frame: Mutex<Vec<u8>>,
+    }
+
+    impl Association {
+        // Set by the watcher, see `Socks5Datagram::watch()`, or when tunneling by
+        // a failed read or write on the stream.
+        fn is_lost(&self) -> bool {
+            self.lost.load(Ordering::Acquire)
+        }
+
+        fn check(&self) -> io::Result<()> {
+            if self.is_lost() {
+                return Err(Error::ControlConnectionLost {}.into_io());
+            }
+            Ok(())
+        }
+
+        // Reads the next UDP over TCP packet from the stream.
+        fn read_frame(&self) -> io::Result<Vec<u8>> {
+            fill_frame(
+                &self.stream.socket,
+                &mut self.frame.lock().unwrap_or_else(PoisonError::into_inner),
+            )
+        }
+    }
+
+    impl Drop for Association {
+        fn drop(&mut self) {
+            // Ends the watcher, which reads from a handle to the same connection.
+            if !self.relay_addrs.is_empty() {
+                let _ = self.stream.socket.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Sends an empty datagram from `socket` to itself, waking up a receive
+    // blocked on it. An empty datagram is never a SOCKS datagram, so receives
+    // skip it. A connected socket only accepts it once connected to itself,
+    // which is fine as the association is lost, reassociating connects it to
+    // the new relay.
+    fn wake(socket: &UdpSocket, connected: bool) -> io::Result<()> {
+        let mut addr = socket.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if connected {
+            socket.connect(addr)?;
+            socket.send(&[])?;
+        } else {
+            socket.send_to(&[], addr)?;
+        }
+        Ok(())
+    }
+
+    // Reads until `frame` holds a whole UDP over TCP packet and takes it.
+    // Whatever a timeout interrupts stays in `frame` for the next call.
+    fn fill_frame(mut socket: &TcpStream, frame: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        loop {
+            let want = frame_len(frame)?;
+            let have = frame.len();
+            if have == want {
+                return Ok(mem::take(frame));
+            }
+
+            frame.resize(want, 0);
+            let read = socket.read(&mut frame[have..]);
+            frame.truncate(have + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Returns the length of the packet starting in `frame`, or as much of it as
+    // can be told from what was read so far.
+    fn frame_len(frame: &[u8]) -> io::Result<usize> {
+        let addr_len = match *frame {
+            [] => return Ok(1),
+            [UOT_IPV4, ..] => 4,
+            [UOT_IPV6, ..] => 16,
+            [UOT_DOMAIN] => return Ok(2),
+            [UOT_DOMAIN, len, ..] => 1 + usize::from(len),
+            [code, ..] => return Err(Error::SOCKS5InvalidAddressType { code }.into_io()),
+        };
+        // address type, address and port, then the payload length
+        let len = 1 + addr_len + 2;
+        match frame.get(len..len + 2) {
+            Some(&[hi, lo]) => Ok(len + 2 + usize::from(u16::from_be_bytes([hi, lo]))),
+            _ => Ok(len + 2),
+        }
+    }
+
+    // Parses a whole UDP over TCP packet, returning where its payload starts.
+    fn read_frame_header(frame: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        frame[0] = match frame[0] {
+            UOT_IPV4 => 1,
+            UOT_IPV6 => 4,
+            _ => 3,
+        };
+        let mut header = &frame[..];
+        let addr = read_addr(&mut header)?;
+        Ok((frame.len() - header.len() + 2, addr))
+    }
+
+    /// What is needed to redo the UDP ASSOCIATE.
+    #[derive(Debug)]
+    struct Reassociate {
+        proxy: Vec<SocketAddr>,
+        credentials: Option<(String, String)>,
+        options: DatagramOptions,
+        connect_timeout: Option<Duration>,
+    }
+
+    /// How datagrams get to the relay.
+    #[derive(Debug, Clone)]
+    enum Transport {
+        Connected,
+        Unconnected,
+        // Frames are written whole, so writes are serialized. Reads are
+        // serialized by the association.
+        Tcp { write: Arc<Mutex<()>> },
+    }
+
+    /// A version of the sing-box UDP over TCP protocol, see
+    /// `DatagramOptions::udp_over_tcp()`.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+    pub enum UdpOverTcp {
+        /// Connects to `sp.udp-over-tcp.arpa` and sends packets right away.
+        V1,
+        /// Connects to `sp.v2.udp-over-tcp.arpa` and sends a request first.
+        V2,
+    }
+
+    impl UdpOverTcp {
+        const fn magic_address(self) -> &'static str {
+            match self {
+                Self::V1 => "sp.udp-over-tcp.arpa",
+                Self::V2 => "sp.v2.udp-over-tcp.arpa",
+            }
+        }
+    }
+
+    /// Options used when creating a `Socks5Datagram`.
+    #[derive(Debug, Clone, Default)]
+    #[allow(clippy::struct_excessive_bools)] // Independent options, not a state.
+    pub struct DatagramOptions {
+        unconnected: bool,
+        advertise_source: bool,
+        udp_over_tcp: Option<UdpOverTcp>,
+        reassociate: bool,
+        resolve: resolve::Settings,
+    }
+
+    impl DatagramOptions {
+        /// Creates the default options.
+        #[must_use]
+        pub const fn new() -> Self {
+            Self {
+                unconnected: false,
+                advertise_source: false,
+                udp_over_tcp: None,
+                reassociate: false,
+                resolve: resolve::Settings::new(),
+            }
+        }
+
+        /// Leaves the UDP socket unconnected.
+        ///
+        /// Instead of connecting the socket to the relay, datagrams are sent to
+        /// the first relay address and incoming datagrams from any other source
+        /// than the relay addresses are dropped. Use this when the relay address
+        /// resolves to several addresses or replies come from a different one.
+        #[must_use]
+        pub const fn unconnected(mut self, unconnected: bool) -> Self {
+            self.unconnected = unconnected;
+            self
+        }
+
+        /// Sends the address of the UDP socket in the UDP ASSOCIATE request.
+        ///
+        /// By default `0.0.0.0:0` is sent, as we do not know our address from the
+        /// perspective of the proxy. Strict proxies may use this address to limit
+        /// who can send through the relay. If the UDP socket is bound to an
+        /// unspecified address, the local IP of the TCP connection is sent with
+        /// the port of the UDP socket.
+        #[must_use]
+        pub const fn advertise_source(mut self, advertise_source: bool) -> Self {
+            self.advertise_source = advertise_source;
+            self
+        }
+
+        /// Tunnels datagrams over TCP with the UDP over TCP protocol of
+        /// shadowsocks and sing-box, instead of a UDP ASSOCIATE.
+        ///
+        /// The proxy is asked to CONNECT to the magic address of `version`. With
+        /// `UdpOverTcp::V2` a request follows that leaves the destination open.
+        /// Each datagram is then sent on the `Socks5Stream` as its address, in
+        /// the SOCKS5 format but with address types 0 (IPv4), 1 (IPv6) and 2
+        /// (domain), followed by the payload length as a big endian `u16` and the
+        /// payload. The UDP socket is still bound, but carries no traffic.
+        ///
+        /// # Notes
+        /// The proxy has to support this protocol, like sing-box does. Datagrams
+        /// are never fragmented.
+        ///
+        /// Cannot be combined with `unconnected` or `advertise_source`, as there
+        /// is no UDP relay, binding then fails with `Error::IncompatibleOptions`.
+        #[must_use]
+        pub const fn udp_over_tcp(mut self, version: Option<UdpOverTcp>) -> Self {
+            self.udp_over_tcp = version;
+            self
+        }
+
+        /// Redoes the UDP ASSOCIATE when the control connection is lost.
+        ///
+        /// The proxy ends the association when the TCP control connection
+        /// closes. By default the next send or receive then fails with
+        /// `Error::ControlConnectionLost`. With this set, a new control
+        /// connection is made to the same proxy instead, keeping the UDP socket
+        /// and switching to the new relay address. Datagrams in flight are lost.
+        #[must_use]
+        pub const fn reassociate(mut self, reassociate: bool) -> Self {
+            self.reassociate = reassociate;
+            self
+        }
+
+        /// Sets the `Resolver` for the host names of the proxy, the local
+        /// address and a relay address given as a domain, like
+        /// `ConnectOptions::resolver()`.
+        #[must_use]
+        pub fn resolver(mut self, resolver: Option<Arc<dyn Resolver>>) -> Self {
+            self.resolve.set_resolver(resolver);
+            self
+        }
+
+        /// Forbids resolving domain names locally for this socket, like
+        /// `ConnectOptions::dns_leak_protection()`.
+        #[must_use]
+        pub fn dns_leak_protection(mut self, enabled: bool) -> Self {
+            self.resolve.set_leak_protection(enabled);
+            self
+        }
+    }
+
+    /// Reassembly queue and timer from RFC 1928 section 7.
+    #[derive(Debug)]
+    struct Reassembly {
+        timeout: Duration,
+        started: Option<Instant>,
+        position: u8,
+        addr: Option<TargetAddr>,
+        data: Vec<u8>,
+    }
+
+    impl Reassembly {
+        const fn new(timeout: Duration) -> Self {
+            Self {
+                timeout,
+                started: None,
+                position: 0,
+                addr: None,
+                data: Vec::new(),
+            }
+        }
+
+        fn reset(&mut self) {
+            self.started = None;
+            self.position = 0;
+            self.addr = None;
+            self.data.clear();
+        }
+
+        /// Queues the fragment in `buf[..len]`, copying the whole datagram into
+        /// `buf` once the last fragment has arrived.
+        fn push(
+            &mut self,
+            frag: u8,
+            addr: TargetAddr,
+            buf: &mut [u8],
+            len: usize,
+        ) -> Option<(usize, TargetAddr)> {
+            let position = frag & MAX_FRAGMENTS;
+
+            if self
+                .started
+                .map_or(false, |started| started.elapsed() > self.timeout)
+            {
+                self.reset();
+            }
+
+            // A lower (or repeated) position abandons the current sequence, a gap
+            // means a fragment was lost and the sequence can never complete.
+            if position != self.position + 1 || self.addr.as_ref().map_or(false, |a| *a != addr) {
+                self.reset();
+                if position == 0 {
+                    return Some((len, addr));
+                }
+                if position != 1 {
+                    return None;
+                }
+            }
+
+            if self.started.is_none() {
+                self.started = Some(Instant::now());
+                self.addr = Some(addr);
+            }
+            self.position = position;
+            self.data.extend_from_slice(&buf[..len]);
+
+            if frag & END_OF_FRAGMENTS == 0 {
+                return None;
+            }
+
+            let len = cmp::min(self.data.len(), buf.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            let addr = self.addr.take();
+            self.reset();
+            addr.map(|addr| (len, addr))
+        }
+    }
+
+    fn read_header<R: Read>(header: &mut R) -> io::Result<(u8, TargetAddr)> {
+        {
+            let bytes = header.read_be_u16()?;
+            if bytes != 0 {
+                return Err(Error::InvalidReservedBytes { bytes }.into());
+            }
+        }
+        let frag = header.read_be_u8()?;
+        let addr = read_addr(header)?;
+        Ok((frag, addr))
+    }
+
+    impl Socks5Datagram {
+        /// Creates a UDP socket bound to the specified address which will have its
+        /// traffic routed through the specified proxy.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind<T, U>(proxy: T, addr: U, connect_timeout: Option<Duration>) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToResolvedAddrs,
+        {
+            Self::bind_internal(
+                proxy,
+                addr,
+                &Authentication::None,
+                &DatagramOptions::new(),
+                connect_timeout,
+            )
+        }
+
+        /// Creates a UDP socket bound to the specified address which will have its
+        /// traffic routed through the specified proxy. The given username and password
+        /// is used to authenticate to the SOCKS proxy.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_password<T, U>(
+            proxy: T,
+            addr: U,
+            username: &str,
+            password: &str,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToResolvedAddrs,
+        {
+            let auth = Authentication::Password { username, password };
+            Self::bind_internal(proxy, addr, &auth, &DatagramOptions::new(), connect_timeout)
+        }
+
+        /// Creates a UDP socket bound to the specified address which will have its
+        /// traffic routed through the specified proxy, using the given `options`.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::InvalidInput, socks2::Error::IncompatibleOptions)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_options<T, U>(
+            proxy: T,
+            addr: U,
+            options: &DatagramOptions,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToResolvedAddrs,
+        {
+            Self::bind_internal(proxy, addr, &Authentication::None, options, connect_timeout)
+        }
+
+        /// Creates a UDP socket bound to the specified address which will have its
+        /// traffic routed through the specified proxy, using the given `options`.
+        /// The given username and password is used to authenticate to the SOCKS proxy.
+        ///
+        /// # Notes
+        /// See `Socks5Stream::connect()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::InvalidInput, socks2::Error::IncompatibleOptions)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn bind_with_password_and_options<T, U>(
+            proxy: T,
+            addr: U,
+            username: &str,
+            password: &str,
+            options: &DatagramOptions,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToResolvedAddrs,
+        {
+            let auth = Authentication::Password { username, password };
+            Self::bind_internal(proxy, addr, &auth, options, connect_timeout)
+        }
+
+        fn bind_internal<T, U>(
+            proxy: T,
+            addr: U,
+            auth: &Authentication,
+            options: &DatagramOptions,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Self>
+        where
+            T: ToResolvedAddrs,
+            U: ToResolvedAddrs,
+        {
+            if options.udp_over_tcp.is_some() {
+                let second = if options.unconnected {
+                    Some("unconnected")
+                } else if options.advertise_source {
+                    Some("advertise_source")
+                } else {
+                    None
+                };
+                if let Some(second) = second {
+                    return Err(Error::IncompatibleOptions {
+                        first: "udp_over_tcp",
+                        second,
+                    }
+                    .into_io());
+                }
+            }
+
+            // Resolved once, in case we have to reassociate.
+            let resolver = options.resolve.resolver();
+            let proxy: Vec<SocketAddr> = proxy.to_resolved_addrs(resolver)?;
+            let socket = UdpSocket::bind(&addr.to_resolved_addrs(resolver)?[..])?;
+            let association = Self::associate(&proxy, &socket, auth, options, connect_timeout)?;
+
+            let transport = if options.udp_over_tcp.is_some() {
+                Transport::Tcp {
+                    write: Arc::new(Mutex::new(())),
+                }
+            } else if options.unconnected {
+                Transport::Unconnected
+            } else {
+                Transport::Connected
+            };
+
+            let reassociate = options.reassociate.then(|| {
+                let credentials = match *auth {
+                    Authentication::Password { username, password } => {
+                        Some((username.to_owned(), password.to_owned()))
+                    }
+                    Authentication::None => None,
+                };
+                Arc::new(Reassociate {
+                    proxy,
+                    credentials,
+                    options: options.clone(),
+                    connect_timeout,
+                })
+            });
+
+            let association = Arc::new(association);
+            let datagram = Self {
+                socket,
+                proxy_addr: association.stream.proxy_addr.clone(),
+                relay_addrs: association.relay_addrs.clone(),
+                association: Arc::new(RwLock::new(Arc::clone(&association))),
+                reassociate,
+                transport,
+                reassembly: None,
+                fragment_size: None,
+                peer: None,
+                #[cfg(target_os = "linux")]
+                batch: Mutex::default(),
+            };
+            datagram.watch(&association)?;
+            Ok(datagram)
+        }
+
+        fn associate(
+            proxy: &[SocketAddr],
+            socket: &UdpSocket,
+            auth: &Authentication,
+            options: &DatagramOptions,
+            connect_timeout: Option<Duration>,
+        ) -> io::Result<Association> {
+            let stream = if let Some(version) = options.udp_over_tcp {
+                let dst = TargetAddr::Domain(version.magic_address().to_owned(), 0);
+                let mut stream =
+                    Socks5Stream::connect_raw(1, proxy, &dst, auth, &connect_timeout.into())?;
+                if version == UdpOverTcp::V2 {
+                    // IsConnect is false, each packet carries its destination.
+                    let mut request = [0; 1 + 7];
+                    let dst = TargetAddr::Ip(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+                    write_addr(&mut request[1..], &dst)?;
+                    stream.socket.write_all(&request)?;
+                }
+                stream
+            } else if options.advertise_source {
+                let tcp = tcp_stream_connect(proxy, &connect_timeout.into())?;
+                let mut src = socket.local_addr()?;
+                if src.ip().is_unspecified() {
+                    src.set_ip(tcp.local_addr()?.ip());
+                }
+                Socks5Stream::handshake(tcp, 3, &src, auth)?
+            } else {
+                // we don't know what our IP is from the perspective of the proxy, so
+                // don't try to pass `addr` in here.
+                #[allow(clippy::ip_constant)]
+                let dst = TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(0, 0, 0, 0),
+                    0,
+                )));
+                Socks5Stream::connect_raw(3, proxy, &dst, auth, &connect_timeout.into())?
+            };
+
+            let relay_addrs = if options.udp_over_tcp.is_some() {
+                // Loss shows up as a read or write error on the stream itself.
+                vec![]
+            } else {
+                // Watched for loss, see `watch()`.
+                if options.unconnected {
+                    Self::resolve_relay(&stream, &options.resolve)?
+                } else {
+                    socket.connect(&Self::resolve_relay(&stream, &options.resolve)?[..])?;
+                    vec![socket.peer_addr()?]
+                }
+            };
+
+            Ok(Association {
+                stream,
+                relay_addrs,
+                lost: AtomicBool::new(false),
+                frame: Mutex::new(vec![]),
+            })
+        }
+
+        // Returns the current association, redoing it first if it was lost.
+        fn association(&self) -> io::Result<Arc<Association>> {
+            let current = Arc::clone(
+                &self
+                    .association
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            if !current.is_lost() {
+                return Ok(current);
+            }
+            let Some(reassociate) = &self.reassociate else {
+                return Err(Error::ControlConnectionLost {}.into_io());
+            };
+
+            let mut association = self
+                .association
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            // Another handle may have beaten us to it.
+            if !Arc::ptr_eq(&association, &current) {
+                return Ok(Arc::clone(&association));
+            }
+
+            let auth = match reassociate.credentials {
+                Some((ref username, ref password)) => {
+                    Authentication::Password { username, password }
+                }
+                None => Authentication::None,
+            };
+            let new = Arc::new(Self::associate(
+                &reassociate.proxy,
+                &self.socket,
+                &auth,
+                &reassociate.options,
+                reassociate.connect_timeout,
+            )?);
+            if let Transport::Tcp { .. } = self.transport {
+                new.stream
+                    .socket
+                    .set_read_timeout(current.stream.socket.read_timeout()?)?;
+                new.stream
+                    .socket
+                    .set_write_timeout(current.stream.socket.write_timeout()?)?;
+            }
+            *association = Arc::clone(&new);
+            self.watch(&new)?;
+            drop(association);
+            Ok(new)
+        }
+
+        // The proxy sends nothing on the control connection, so instead of every
+        // send and receive checking it, a thread blocks reading it. Once it is
+        // lost, the association is marked lost and a blocked receive is woken up.
+        fn watch(&self, association: &Arc<Association>) -> io::Result<()> {
+            let connected = match self.transport {
+                Transport::Connected => true,
+                Transport::Unconnected => false,
+                Transport::Tcp { .. } => return Ok(()),
+            };
+            let mut control = association.stream.socket.try_clone()?;
+            let socket = self.socket.try_clone()?;
+            let shared = Arc::downgrade(&self.association);
+            let watched = Arc::downgrade(association);
+
+            thread::Builder::new()
+                .name("socks2-udp-watch".to_owned())
+                .spawn(move || {
+                    let mut buf = [0; 64];
+                    loop {
+                        match control.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(_) => {}
+                            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                            Err(_) => break,
+                        }
+                    }
+
+                    // Dropping the association shuts the connection down, that is
+                    // not a loss.
+                    let (Some(shared), Some(association)) = (shared.upgrade(), watched.upgrade())
+                    else {
+                        return;
+                    };
+                    // Taken so that no send or reassociation is under way.
+                    let current = shared.write().unwrap_or_else(PoisonError::into_inner);
+                    association.lost.store(true, Ordering::Release);
+                    // A replaced association no longer owns the socket.
+                    if Arc::ptr_eq(&current, &association) {
+                        let _ = wake(&socket, connected);
+                    }
+                    drop(current);
+                })?;
+            Ok(())
+        }
+
+        // Runs `f` with the current association. If `f` finds it lost, or when
+        // tunneling a read or write on the stream fails, `f` is retried once
+        // after reassociating.
+        fn with_association<F, R>(&self, mut f: F) -> io::Result<R>
+        where
+            F: FnMut(&Association) -> io::Result<R>,
+        {
+            let association = self.association()?;
+            match f(&association) {
+                Err(err)
+                    if matches!(
+                        unwrap_io_to_socks2_error(&err),
+                        Some(Error::ControlConnectionLost {})
+                    ) || self.is_connection_error(&err) =>
+                {
+                    association.lost.store(true, Ordering::Release);
+                    f(&*self.association()?)
+                }
+                result => result,
+            }
+        }
+
+        fn is_connection_error(&self, err: &io::Error) -> bool {
+            matches!(self.transport, Transport::Tcp { .. })
+                && !is_io_socks2_error(err)
+                && !matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                )
+        }
+
+        // Like `Socks4Listener::proxy_addr`, an unspecified relay address means
+        // the relay is on the host we are talking to over TCP.
+        fn resolve_relay(
+            stream: &Socks5Stream,
+            settings: &resolve::Settings,
+        ) -> io::Result<Vec<SocketAddr>> {
+            let addrs: Vec<SocketAddr> = match stream.proxy_addr {
+                TargetAddr::Ip(addr) if addr.ip().is_unspecified() => {
+                    vec![SocketAddr::new(
+                        stream.socket.peer_addr()?.ip(),
+                        addr.port(),
+                    )]
+                }
+                TargetAddr::Ip(addr) => vec![addr],
+                TargetAddr::Domain(ref domain, port) => settings.resolve(domain, port)?,
+            };
+
+            if addrs.is_empty() {
+                return Err(Error::NoResolveSocketAddrs {}.into_io());
+            }
+            Ok(addrs)
+        }
+
+        /// Enables reassembly of fragmented datagrams in `recv_from`.
+        ///
+        /// Fragments are queued until the last one arrives. The queue is dropped
+        /// if `timeout` passes before the datagram is complete. `None` disables
+        /// reassembly, which is the default, and fragments are then rejected.
+        ///
+        /// # Notes
+        /// `timeout` will be raised to `MIN_REASSEMBLY_TIMEOUT` if it is lower.
+        ///
+        /// Clones made before this call keep their previous setting.
+        pub fn set_reassembly_timeout(&mut self, timeout: Option<Duration>) {
+            self.reassembly = timeout.map(|t| {
+                Arc::new(Mutex::new(Reassembly::new(cmp::max(
+                    t,
+                    MIN_REASSEMBLY_TIMEOUT,
+                ))))
+            });
+        }
+
+        /// Enables fragmentation in `send_to`.
+        ///
+        /// Datagrams with a payload larger than `size` bytes are split into
+        /// fragments of at most `size` bytes each. The SOCKS header is not
+        /// counted. `None` disables fragmentation, which is the default.
+        ///
+        /// # Notes
+        /// Only use this if the proxy supports reassembly, most do not. Has no
+        /// effect when tunneling over TCP.
+        pub fn set_fragment_size(&mut self, size: Option<usize>) {
+            self.fragment_size = size.map(|s| cmp::max(s, 1));
+        }
+
+        /// Like `UdpSocket::send_to`.
+        ///
+        /// # Note
+        /// The SOCKS protocol inserts a header at the beginning of the message. The
+        /// header will be 10 bytes for an IPv4 address, 22 bytes for an IPv6
+        /// address, and 7 bytes plus the length of the domain for a domain address.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send_to<A>(&self, buf: &[u8], addr: &A) -> io::Result<usize>
+        where
+            A: ToTargetAddr,
+        {
+            let addr = addr.to_target_addr()?;
+
+            let mut header = [0; MAX_ADDR_LEN + 3];
+            // first two bytes are reserved at 0
+            // third byte is the fragment id at 0
+            let len = write_addr(&mut header[3..], &addr)?;
+            self.send_with_header(&mut header[..len + 3], buf)
+        }
+
+        fn send_with_header(&self, header: &mut [u8], buf: &[u8]) -> io::Result<usize> {
+            match self.fragment_size {
+                Some(size)
+                    if buf.len() > size && !matches!(self.transport, Transport::Tcp { .. }) =>
+                {
+                    self.send_fragments(header, buf, size)
+                }
+                _ => self.send_packet([header, buf]),
+            }
+        }
+
+        /// Sets the default destination for `send` and the only source accepted
+        /// by `recv`.
+        ///
+        /// The SOCKS header is encoded once here instead of on every send.
+        /// Calling this again replaces the destination.
+        ///
+        /// # Notes
+        /// Datagrams are matched by the address in their SOCKS header. Proxies
+        /// usually put the resolved IP address there, so when `target` is a
+        /// `TargetAddr::Domain` replies may never match.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn connect<A>(&mut self, target: &A) -> io::Result<()>
+        where
+            A: ToTargetAddr,
+        {
+            let target = target.to_target_addr()?;
+            let mut header = vec![0; MAX_ADDR_LEN + 3];
+            let len = write_addr(&mut header[3..], &target)?;
+            header.truncate(len + 3);
+            self.peer = Some((target, header));
+            Ok(())
+        }
+
+        /// Returns the destination set by `connect`.
+        #[must_use]
+        pub fn peer_addr(&self) -> Option<&TargetAddr> {
+            self.peer.as_ref().map(|(addr, _)| addr)
+        }
+
+        /// Like `UdpSocket::send`, sends to the destination set by `connect`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::NotConnected, socks2::Error::DatagramNotConnected)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            let Some((_, ref encoded)) = self.peer else {
+                return Err(Error::DatagramNotConnected {}.into_io());
+            };
+
+            // The fragment id is written into the header when fragmenting.
+            let mut header = [0; MAX_ADDR_LEN + 3];
+            let header = &mut header[..encoded.len()];
+            header.copy_from_slice(encoded);
+            self.send_with_header(header, buf)
+        }
+
+        /// Like `UdpSocket::recv`, receives from the destination set by `connect`.
+        ///
+        /// Datagrams from any other address are dropped.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::NotConnected, socks2::Error::DatagramNotConnected)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((ref target, _)) = self.peer else {
+                return Err(Error::DatagramNotConnected {}.into_io());
+            };
+
+            loop {
+                let (len, addr) = self.recv_from(buf)?;
+                if addr == *target {
+                    return Ok(len);
+                }
+            }
+        }
+
+        fn send_packet(&self, bufs: [&[u8]; 2]) -> io::Result<usize> {
+            self.with_association(|association| match self.transport {
+                // TODO: Use write_vectored?
+                Transport::Connected => {
+                    // The watcher connects the socket to itself once the
+                    // association is lost, which cannot happen while this is held.
+                    let _current = self.lock_association();
+                    association.check()?;
+                    self.socket.writev(bufs)
+                }
+                Transport::Unconnected => {
+                    let packet = bufs.concat();
+                    self.socket.send_to(&packet, association.relay_addrs[0])
+                }
+                Transport::Tcp { ref write, .. } => {
+                    let [header, buf] = bufs;
+                    let Ok(len) = u16::try_from(buf.len()) else {
+                        return Err(Error::DatagramTooLarge { size: buf.len() }.into_io());
+                    };
+
+                    // The address follows RSV and FRAG, only its type is numbered
+                    // differently.
+                    let atyp = match header[3] {
+                        1 => UOT_IPV4,
+                        4 => UOT_IPV6,
+                        _ => UOT_DOMAIN,
+                    };
+                    let packet = [&[atyp][..], &header[4..], &len.to_be_bytes(), buf].concat();
+                    let _guard = write.lock().unwrap_or_else(PoisonError::into_inner);
+                    (&association.stream.socket).write_all(&packet)?;
+                    Ok(header.len() + buf.len())
+                }
+            })
+        }
+
+        fn send_fragments(&self, header: &mut [u8], buf: &[u8], size: usize) -> io::Result<usize> {
+            let count = (buf.len() + size - 1) / size;
+            if count > MAX_FRAGMENTS as usize {
+                return Err(Error::TooManyFragments { count }.into_io());
+            }
+
+            let mut sent = 0;
+            for (position, chunk) in (1..=MAX_FRAGMENTS).zip(buf.chunks(size)) {
+                header[2] = if position as usize == count {
+                    position | END_OF_FRAGMENTS
+                } else {
+                    position
+                };
+                sent += self.send_packet([header, chunk])?;
+            }
+            Ok(sent)
+        }
+
+        /// Sends many datagrams at once, returning how many were sent.
+        ///
+        /// On Linux this uses a single `sendmmsg` call, on other platforms or when
+        /// tunneling, unconnected or fragmenting, `send_to` is called for each
+        /// datagram.
+        ///
+        /// # Notes
+        /// Like `sendmmsg`, an error is only returned if the first datagram could
+        /// not be sent.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send_many<A>(&self, datagrams: &[(&[u8], A)]) -> io::Result<usize>
+        where
+            A: ToTargetAddr,
+        {
+            #[cfg(target_os = "linux")]
+            if matches!(self.transport, Transport::Connected) && self.fragment_size.is_none() {
+                let mut headers = Vec::with_capacity(datagrams.len());
+                for (_, addr) in datagrams {
+                    let mut header = [0; MAX_ADDR_LEN + 3];
+                    let len = write_addr(&mut header[3..], &addr.to_target_addr()?)?;
+                    headers.push((header, len + 3));
+                }
+
+                let msgs: Vec<[&[u8]; 2]> = headers
+                    .iter()
+                    .zip(datagrams)
+                    .map(|((header, len), (buf, _))| [&header[..*len], *buf])
+                    .collect();
+                return self.with_association(|association| {
+                    // See `send_packet()`.
+                    let _current = self.lock_association();
+                    association.check()?;
+                    self.socket.sendmmsgv(&msgs)
+                });
+            }
+
+            for (sent, (buf, addr)) in datagrams.iter().enumerate() {
+                if let Err(err) = self.send_to(buf, addr) {
+                    return if sent == 0 { Err(err) } else { Ok(sent) };
+                }
+            }
+            Ok(datagrams.len())
+        }
+
+        /// Receives many datagrams at once.
+        ///
+        /// Blocks until at least one datagram is available. The returned lengths
+        /// and addresses are in the same order as `bufs`, so the first entry
+        /// describes `bufs[0]`.
+        ///
+        /// On Linux this uses a single `recvmmsg` call, on other platforms or when
+        /// tunneling, unconnected or reassembling, only one datagram is received
+        /// with `recv_from`.
+        ///
+        /// # Notes
+        /// Unlike `recv_from`, which returns an error for them, datagrams with an
+        /// invalid header or a fragment id are dropped without telling the caller,
+        /// so the result may be empty and is never longer than `bufs`.
+        ///
+        /// The buffers around `bufs` are kept by the handle and reused by the next
+        /// call, so calls on the same handle wait for each other. Receive from
+        /// several threads with handles made by `try_clone`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_many(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, TargetAddr)>> {
+            #[cfg(target_os = "linux")]
+            if matches!(self.transport, Transport::Connected) && self.reassembly.is_none() {
+                let mut batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+                let RecvBatch {
+                    heads,
+                    tails,
+                    lens,
+                    mmsg,
+                } = &mut *batch;
+                heads.resize(bufs.len(), [0; HEAD_LEN]);
+                tails.resize(bufs.len(), [0; TAIL_LEN]);
+                lens.resize(bufs.len(), 0);
+
+                let msgs = heads
+                    .iter_mut()
+                    .zip(bufs.iter_mut())
+                    .zip(tails.iter_mut())
+                    .map(|((head, buf), tail)| [&mut head[..], &mut **buf, &mut tail[..]]);
+                let association = self.association()?;
+                let received = self.socket.recvmmsgv(msgs, lens, mmsg)?;
+                if association.is_lost() {
+                    // Reassociates, the datagrams of the lost association are dropped.
+                    drop(batch);
+                    self.association()?;
+                    return Ok(vec![]);
+                }
+
+                let mut datagrams = Vec::with_capacity(received);
+                for i in 0..received {
+                    let Ok((len, 0, addr)) =
+                        Self::join_packet(&heads[i], bufs[i], &tails[i], lens[i])
+                    else {
+                        continue;
+                    };
+
+                    // Keep entries lined up with `bufs` when one was dropped.
+                    let j = datagrams.len();
+                    let len = if i == j {
+                        len
+                    } else {
+                        let (front, back) = bufs.split_at_mut(i);
+                        let len = cmp::min(len, front[j].len());
+                        front[j][..len].copy_from_slice(&back[0][..len]);
+                        len
+                    };
+                    datagrams.push((len, addr));
+                }
+                drop(batch);
+                return Ok(datagrams);
+            }
+
+            match bufs.first_mut() {
+                Some(buf) => Ok(vec![self.recv_from(buf)?]),
+                None => Ok(vec![]),
+            }
+        }
+
+        /// Like `UdpSocket::recv_from`.
+        ///
+        /// The datagram is received with the SOCKS header in front of `buf` and
+        /// the header is parsed in place. The header for an IPv4 address is exactly
+        /// as long as the space in front, so the payload is received into place.
+        /// With any other header the payload is moved to the start of `buf`, see
+        /// `recv_from_view()` to avoid that.
+        ///
+        /// # Notes
+        /// With reassembly enabled, this only returns once a whole datagram has
+        /// been received. Datagrams that do not fit in `buf` are truncated.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+            loop {
+                let (len, frag, addr) = self.recv_fragment(buf)?;
+
+                let Some(reassembly) = &self.reassembly else {
+                    if frag != 0 {
+                        return Err(Error::InvalidFragmentID { fid: frag }.into_io());
+                    }
+                    return Ok((len, addr));
+                };
+
+                // A standalone datagram (FRAG 0) also abandons any queued fragments.
+                let datagram = reassembly
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(frag, addr, buf, len);
+                if let Some(datagram) = datagram {
+                    return Ok(datagram);
+                }
+            }
+        }
+
+        fn recv_fragment(&self, buf: &mut [u8]) -> io::Result<(usize, u8, TargetAddr)> {
+            self.with_association(|association| {
+                let mut head = [0; HEAD_LEN];
+                let mut tail = [0; TAIL_LEN];
+                let len = match self.transport {
+                    Transport::Connected => loop {
+                        let len = self.socket.readv([&mut head, buf, &mut tail])?;
+                        association.check()?;
+                        // An empty datagram only wakes us up, see `wake()`.
+                        if len != 0 {
+                            break len;
+                        }
+                    },
+                    Transport::Unconnected => loop {
+                        let (len, src) = self.socket.readv_from([&mut head, buf, &mut tail])?;
+                        association.check()?;
+                        if association.relay_addrs.contains(&src) {
+                            break len;
+                        }
+                    },
+                    Transport::Tcp { .. } => {
+                        let mut frame = association.read_frame()?;
+                        let (start, addr) = read_frame_header(&mut frame)?;
+                        let len = cmp::min(frame.len() - start, buf.len());
+                        buf[..len].copy_from_slice(&frame[start..start + len]);
+                        return Ok((len, 0, addr));
+                    }
+                };
+                Self::join_packet(&head, buf, &tail, len)
+            })
+        }
+
+        // Parses a datagram that was read into `head`, `buf` and `tail`, moving
+        // the payload to the start of `buf` unless it is there already.
+        fn join_packet(
+            head: &[u8; HEAD_LEN],
+            buf: &mut [u8],
+            tail: &[u8; TAIL_LEN],
+            len: usize,
+        ) -> io::Result<(usize, u8, TargetAddr)> {
+            let in_head = cmp::min(len, head.len());
+            let in_buf = cmp::min(len - in_head, buf.len());
+            let mut packet = (&head[..in_head])
+                .chain(&buf[..in_buf])
+                .chain(&tail[..len - in_head - in_buf]);
+            let (frag, addr) = read_header(&mut packet)?;
+
+            let (packet, tail) = packet.into_inner();
+            let (head, payload) = packet.into_inner();
+            let (start, payload_len) = (in_buf - payload.len(), payload.len());
+
+            // A header shorter than `head` leaves payload there that goes in front,
+            // a longer one leaves a gap in `buf`. Anything that no longer fits in
+            // `buf` is truncated.
+            let moved = cmp::min(head.len(), buf.len());
+            let kept = cmp::min(payload_len, buf.len() - moved);
+            if start != moved {
+                buf.copy_within(start..start + kept, moved);
+            }
+            buf[..moved].copy_from_slice(&head[..moved]);
+            let len = moved + kept;
+            let extra = cmp::min(tail.len(), buf.len() - len);
+            buf[len..len + extra].copy_from_slice(&tail[..extra]);
+
+            Ok((len + extra, frag, addr))
+        }
+
+        /// Like `recv_from`, but the payload is not moved to the start of `buf`.
+        ///
+        /// The whole datagram, including the SOCKS header, is received into `buf`
+        /// and the header is parsed in place. The returned slice is the payload
+        /// that follows it.
+        ///
+        /// # Notes
+        /// `buf` must have room for the header too, see `send_to`. Datagrams that
+        /// do not fit in `buf` are truncated. When tunneling over TCP, the payload
+        /// is copied to the start of `buf`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_from_view<'a>(&self, buf: &'a mut [u8]) -> io::Result<(TargetAddr, &'a [u8])> {
+            loop {
+                let (start, end, frag, addr) = self.recv_packet(buf)?;
+
+                let Some(reassembly) = &self.reassembly else {
+                    if frag != 0 {
+                        return Err(Error::InvalidFragmentID { fid: frag }.into_io());
+                    }
+                    return Ok((addr, &buf[start..end]));
+                };
+
+                let datagram = reassembly
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(frag, addr, &mut buf[start..], end - start);
+                if let Some((len, addr)) = datagram {
+                    return Ok((addr, &buf[start..start + len]));
+                }
+            }
+        }
+
+        // Receives a whole datagram into `buf`, returning where the payload is.
+        fn recv_packet(&self, buf: &mut [u8]) -> io::Result<(usize, usize, u8, TargetAddr)> {
+            self.with_association(|association| {
+                let len = match self.transport {
+                    Transport::Connected => loop {
+                        let len = self.socket.recv(buf)?;
+                        association.check()?;
+                        // See `recv_fragment()`.
+                        if len != 0 {
+                            break len;
+                        }
+                    },
+                    Transport::Unconnected => loop {
+                        let (len, src) = self.socket.recv_from(buf)?;
+                        association.check()?;
+                        if association.relay_addrs.contains(&src) {
+                            break len;
+                        }
+                    },
+                    Transport::Tcp { .. } => {
+                        let mut frame = association.read_frame()?;
+                        let (start, addr) = read_frame_header(&mut frame)?;
+                        let len = cmp::min(frame.len() - start, buf.len());
+                        buf[..len].copy_from_slice(&frame[start..start + len]);
+                        return Ok((0, len, 0, addr));
+                    }
+                };
+
+                let mut payload = &buf[..len];
+                let (frag, addr) = read_header(&mut payload)?;
+                Ok((len - payload.len(), len, frag, addr))
+            })
+        }
+
+        /// Returns the address of the proxy-side UDP socket through which all
+        /// messages will be routed.
+        ///
+        /// # Notes
+        /// This is the address of the first association, see
+        /// `current_proxy_addr()` after reassociating.
+        #[must_use]
+        pub const fn proxy_addr(&self) -> &TargetAddr {
+            &self.proxy_addr
+        }
+
+        /// Returns the resolved addresses of the relay.
+        ///
+        /// Datagrams are sent to the first address. When connected this is the
+        /// only address, otherwise datagrams are accepted from any of them. This
+        /// is empty when tunneling over TCP.
+        ///
+        /// # Notes
+        /// These are the addresses of the first association, see
+        /// `current_relay_addrs()` after reassociating.
+        #[must_use]
+        pub fn relay_addrs(&self) -> &[SocketAddr] {
+            &self.relay_addrs
+        }
+
+        /// Like `proxy_addr()`, but of the current association, which changes
+        /// when reassociating.
+        #[must_use]
+        pub fn current_proxy_addr(&self) -> TargetAddr {
+            self.current().stream.proxy_addr.clone()
+        }
+
+        /// Like `relay_addrs()`, but of the current association, which changes
+        /// when reassociating.
+        #[must_use]
+        pub fn current_relay_addrs(&self) -> Vec<SocketAddr> {
+            self.current().relay_addrs.clone()
+        }
+
+        /// Returns whether the control connection of the current association was
+        /// lost. Sending or receiving will then fail or reassociate, see
+        /// `DatagramOptions::reassociate()`.
+        #[must_use]
+        pub fn is_lost(&self) -> bool {
+            self.current().is_lost()
+        }
+
+        fn current(&self) -> Arc<Association> {
+            Arc::clone(&self.lock_association())
+        }
+
+        fn lock_association(&self) -> RwLockReadGuard<'_, Arc<Association>> {
+            self.association
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Returns a shared reference to the inner socket.
+        #[must_use]
+        pub const fn get_ref(&self) -> &UdpSocket {
+            &self.socket
+        }
+
+        /// Returns a mutable reference to the inner socket.
+        pub fn get_mut(&mut self) -> &mut UdpSocket {
+            &mut self.socket
+        }
+
+        /// Like `UdpSocket::peek_from`.
+        ///
+        /// # Notes
+        /// Fragments are not reassembled, a fragmented datagram is an error like
+        /// when reassembly is disabled. A datagram from another source than the
+        /// relay is an error too, it stays queued until it is received with
+        /// `recv_from`, which drops it.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::InvalidData, socks2::Error::DatagramNotFromRelay)`
+        /// - `io::Error(std::io::ErrorKind::Unsupported, socks2::Error::UnsupportedOverTcp)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+            if let Transport::Tcp { .. } = self.transport {
+                return Err(Error::UnsupportedOverTcp {
+                    operation: "peek_from",
+                }
+                .into_io());
+            }
+
+            self.with_association(|association| {
+                let mut head = [0; HEAD_LEN];
+                let mut tail = [0; TAIL_LEN];
+                let (len, src) = self.socket.peekv_from([&mut head, buf, &mut tail])?;
+                association.check()?;
+                // This includes an empty datagram left by `wake()` when connected.
+                if !association.relay_addrs.contains(&src) {
+                    return Err(Error::DatagramNotFromRelay { addr: src }.into_io());
+                }
+
+                let (len, frag, addr) = Self::join_packet(&head, buf, &tail, len)?;
+                if frag != 0 {
+                    return Err(Error::InvalidFragmentID { fid: frag }.into_io());
+                }
+                Ok((len, addr))
+            })
+        }
+
+        /// Returns the local address of the UDP socket.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+
+        /// Sets the read timeout for receiving datagrams.
+        ///
+        /// # Notes
+        /// When tunneling over TCP this is set on the control connection.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            match self.transport {
+                Transport::Tcp { .. } => self.current().stream.socket.set_read_timeout(dur),
+                _ => self.socket.set_read_timeout(dur),
+            }
+        }
+
+        /// Sets the write timeout for sending datagrams.
+        ///
+        /// # Notes
+        /// When tunneling over TCP this is set on the control connection.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            match self.transport {
+                Transport::Tcp { .. } => self.current().stream.socket.set_write_timeout(dur),
+                _ => self.socket.set_write_timeout(dur),
+            }
+        }
+
+        /// Returns the read timeout for receiving datagrams.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            match self.transport {
+                Transport::Tcp { .. } => self.current().stream.socket.read_timeout(),
+                _ => self.socket.read_timeout(),
+            }
+        }
+
+        /// Returns the write timeout for sending datagrams.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+            match self.transport {
+                Transport::Tcp { .. } => self.current().stream.socket.write_timeout(),
+                _ => self.socket.write_timeout(),
+            }
+        }
+
+        /// Moves the UDP socket into or out of nonblocking mode.
+        ///
+        /// # Notes
+        /// This also applies to clones and halves, they share the socket.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::Unsupported, socks2::Error::UnsupportedOverTcp)`
+        /// - `std::io::ErrorKind::*`
+        pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            if nonblocking && matches!(self.transport, Transport::Tcp { .. }) {
+                // A frame could be cut in half, leaving the stream out of sync.
+                return Err(Error::UnsupportedOverTcp {
+                    operation: "set_nonblocking",
+                }
+                .into_io());
+            }
+            self.socket.set_nonblocking(nonblocking)
+        }
+
+        /// Creates a new independently owned handle to the same association.
+        ///
+        /// The association is kept alive until the last handle is dropped.
+        /// Queued fragments are shared between handles.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self {
+                socket: self.socket.try_clone()?,
+                proxy_addr: self.proxy_addr.clone(),
+                relay_addrs: self.relay_addrs.clone(),
+                association: Arc::clone(&self.association),
+                reassociate: self.reassociate.clone(),
+                transport: self.transport.clone(),
+                reassembly: self.reassembly.clone(),
+                fragment_size: self.fragment_size,
+                peer: self.peer.clone(),
+                #[cfg(target_os = "linux")]
+                batch: Mutex::default(),
+            })
+        }
+
+        /// Splits the datagram into a sending and a receiving half that can be
+        /// moved to different threads.
+        ///
+        /// The association is kept alive until both halves are dropped.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn split(self) -> io::Result<(Socks5DatagramSender, Socks5DatagramReceiver)> {
+            let sender = self.try_clone()?;
+            Ok((Socks5DatagramSender(sender), Socks5DatagramReceiver(self)))
+        }
+    }
+
+    /// The sending half of a `Socks5Datagram`, see `Socks5Datagram::split()`.
+    #[derive(Debug)]
+    pub struct Socks5DatagramSender(Socks5Datagram);
+
+    impl Socks5DatagramSender {
+        /// See `Socks5Datagram::send_to()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send_to<A>(&self, buf: &[u8], addr: &A) -> io::Result<usize>
+        where
+            A: ToTargetAddr,
+        {
+            self.0.send_to(buf, addr)
+        }
+
+        /// See `Socks5Datagram::send_many()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send_many<A>(&self, datagrams: &[(&[u8], A)]) -> io::Result<usize>
+        where
+            A: ToTargetAddr,
+        {
+            self.0.send_many(datagrams)
+        }
+
+        /// See `Socks5Datagram::send()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        /// See `Socks5Datagram::set_write_timeout()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_write_timeout(dur)
+        }
+
+        /// See `Socks5Datagram::local_addr()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+
+        /// See `Socks5Datagram::proxy_addr()`.
+        #[must_use]
+        pub const fn proxy_addr(&self) -> &TargetAddr {
+            self.0.proxy_addr()
+        }
+    }
+
+    /// The receiving half of a `Socks5Datagram`, see `Socks5Datagram::split()`.
+    #[derive(Debug)]
+    pub struct Socks5DatagramReceiver(Socks5Datagram);
+
+    impl Socks5DatagramReceiver {
+        /// See `Socks5Datagram::recv_from()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+            self.0.recv_from(buf)
+        }
+
+        /// See `Socks5Datagram::recv_from_view()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_from_view<'a>(&self, buf: &'a mut [u8]) -> io::Result<(TargetAddr, &'a [u8])> {
+            self.0.recv_from_view(buf)
+        }
+
+        /// See `Socks5Datagram::recv_many()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv_many(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, TargetAddr)>> {
+            self.0.recv_many(bufs)
+        }
+
+        /// See `Socks5Datagram::recv()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+
+        /// See `Socks5Datagram::peek_from()`.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+            self.0.peek_from(buf)
+        }
+
+        /// See `Socks5Datagram::set_read_timeout()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_read_timeout(dur)
+        }
+
+        /// See `Socks5Datagram::set_reassembly_timeout()`.
+        pub fn set_reassembly_timeout(&mut self, timeout: Option<Duration>) {
+            self.0.set_reassembly_timeout(timeout);
+        }
+
+        /// See `Socks5Datagram::local_addr()`.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+
+        /// See `Socks5Datagram::proxy_addr()`.
+        #[must_use]
+        pub const fn proxy_addr(&self) -> &TargetAddr {
+            self.0.proxy_addr()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsFd for Socks5Datagram {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.socket.as_fd()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for Socks5Datagram {
+        fn as_raw_fd(&self) -> RawFd {
+            self.socket.as_raw_fd()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsSocket for Socks5Datagram {
+        fn as_socket(&self) -> BorrowedSocket<'_> {
+            self.socket.as_socket()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsRawSocket for Socks5Datagram {
+        fn as_raw_socket(&self) -> RawSocket {
+            self.socket.as_raw_socket()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsFd for Socks5DatagramSender {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.0.socket.as_fd()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for Socks5DatagramSender {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.socket.as_raw_fd()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsSocket for Socks5DatagramSender {
+        fn as_socket(&self) -> BorrowedSocket<'_> {
+            self.0.socket.as_socket()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsRawSocket for Socks5DatagramSender {
+        fn as_raw_socket(&self) -> RawSocket {
+            self.0.socket.as_raw_socket()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsFd for Socks5DatagramReceiver {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.0.socket.as_fd()
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for Socks5DatagramReceiver {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.socket.as_raw_fd()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsSocket for Socks5DatagramReceiver {
+        fn as_socket(&self) -> BorrowedSocket<'_> {
+            self.0.socket.as_socket()
+        }
+    }
+
+    #[cfg(windows)]
+    impl AsRawSocket for Socks5DatagramReceiver {
+        fn as_raw_socket(&self) -> RawSocket {
+            self.0.socket.as_raw_socket()
+        }
+    }
+}
+
+#[cfg(feature = "udp")]
+pub mod mux {
+    use crate::{v5::udp::Socks5Datagram, Error, TargetAddr, ToTargetAddr};
+    use alloc::{collections::VecDeque, sync::Arc};
+    use core::{
+        cmp,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Condvar, Mutex, MutexGuard, PoisonError},
+        thread,
+        time::Instant,
+    };
+
+    // How often the reader wakes up to expire idle peers and check for shutdown.
+    const TICK: Duration = Duration::from_millis(100);
+    const MAX_DATAGRAM: usize = 65535;
+
+    /// Options used when creating a `UdpMux`.
+    #[derive(Debug, Clone)]
+    pub struct MuxOptions {
+        idle_timeout: Option<Duration>,
+        queue_len: usize,
+    }
+
+    impl Default for MuxOptions {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl MuxOptions {
+        /// Creates the default options, a 60 second idle timeout and a queue of
+        /// 64 datagrams per peer.
+        #[must_use]
+        pub const fn new() -> Self {
+            Self {
+                idle_timeout: Some(Duration::from_secs(60)),
+                queue_len: 64,
+            }
+        }
+
+        /// Closes peers that have not sent or received a datagram for `timeout`.
+        /// `None` keeps peers until their last handle is dropped.
+        #[must_use]
+        pub const fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+            self.idle_timeout = timeout;
+            self
+        }
+
+        /// Sets how many datagrams are queued per peer. Datagrams arriving at a
+        /// full queue are dropped.
+        #[must_use]
+        pub const fn queue_len(mut self, len: usize) -> Self {
+            self.queue_len = len;
+            self
+        }
+    }
+
+    /// Demultiplexes the datagrams of one `Socks5Datagram` by remote address.
+    ///
+    /// A background thread receives datagrams and queues each one for the
+    /// `MuxPeer` of the address in its SOCKS header. Datagrams from addresses
+    /// without a peer are dropped.
+    ///
+    /// # Notes
+    /// The addresses are compared as they appear in the SOCKS header, see
+    /// `Socks5Datagram::connect()`.
+    #[derive(Debug)]
+    pub struct UdpMux {
+        shared: Arc<Shared>,
+    }
+
+    #[derive(Debug)]
+    struct Shared {
+        datagram: Socks5Datagram,
+        peers: Mutex<HashMap<TargetAddr, Arc<Queue>>>,
+        options: MuxOptions,
+        closed: AtomicBool,
+    }
+
+    #[derive(Debug)]
+    struct Queue {
+        state: Mutex<QueueState>,
+        ready: Condvar,
+    }
+
+    #[derive(Debug)]
+    struct QueueState {
+        datagrams: VecDeque<Vec<u8>>,
+        last_active: Instant,
+        closed: bool,
+        // number of `MuxPeer`s using the queue
+        handles: usize,
+    }
+
+    impl UdpMux {
+        /// Starts demultiplexing `datagram` with the default options.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn new(datagram: Socks5Datagram) -> io::Result<Self> {
+            Self::with_options(datagram, &MuxOptions::new())
+        }
+
+        /// Starts demultiplexing `datagram` with the given `options`.
+        ///
+        /// # Notes
+        /// The read timeout of `datagram` is changed, so the background thread
+        /// can expire peers and stop once the `UdpMux` is dropped.
+        ///
+        /// # Errors
+        /// - `std::io::ErrorKind::*`
+        pub fn with_options(datagram: Socks5Datagram, options: &MuxOptions) -> io::Result<Self> {
+            datagram.set_read_timeout(Some(TICK))?;
+            let shared = Arc::new(Shared {
+                datagram,
+                peers: Mutex::new(HashMap::new()),
+                options: options.clone(),
+                closed: AtomicBool::new(false),
+            });
+
+            let reader = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("socks2-udp-mux".to_owned())
+                .spawn(move || reader.run())?;
+            Ok(Self { shared })
+        }
+
+        /// Returns a handle for the datagrams of `addr`.
+        ///
+        /// Handles for the same address share one queue.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::ConnectionAborted, socks2::Error::MuxPeerClosed)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn peer<A>(&self, addr: &A) -> io::Result<MuxPeer>
+        where
+            A: ToTargetAddr,
+        {
+            let addr = addr.to_target_addr()?;
+            let mut peers = self.shared.lock_peers();
+            // Checked under the lock, so the reader cannot miss this queue when
+            // closing.
+            if self.shared.closed.load(Ordering::Acquire) {
+                return Err(Error::MuxPeerClosed {}.into_io());
+            }
+            let queue = Arc::clone(
+                peers
+                    .entry(addr.clone())
+                    .or_insert_with(|| Arc::new(Queue::new())),
+            );
+            queue.lock().handles += 1;
+            drop(peers);
+
+            Ok(MuxPeer {
+                shared: Arc::clone(&self.shared),
+                addr,
+                queue,
+                read_timeout: None,
+            })
+        }
+
+        /// Returns the number of open peers.
+        #[must_use]
+        pub fn peer_count(&self) -> usize {
+            self.shared.lock_peers().len()
+        }
+
+        /// Returns a shared reference to the inner `Socks5Datagram`.
+        #[must_use]
+        pub fn get_ref(&self) -> &Socks5Datagram {
+            &self.shared.datagram
+        }
+    }
+
+    impl Drop for UdpMux {
+        fn drop(&mut self) {
+            self.shared.closed.store(true, Ordering::Release);
+        }
+    }
+
+    impl Shared {
+        fn lock_peers(&self) -> MutexGuard<'_, HashMap<TargetAddr, Arc<Queue>>> {
+            self.peers.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        fn run(&self) {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            let mut last_sweep = Instant::now();
+            while !self.closed.load(Ordering::Acquire) {
+                match self.datagram.recv_from(&mut buf) {
+                    Ok((len, addr)) => {
+                        let queue = self.lock_peers().get(&addr).cloned();
+                        if let Some(queue) = queue {
+                            queue.push(&buf[..len], self.options.queue_len);
+                        }
+                    }
+                    // Timeouts wake us up, malformed datagrams and ICMP errors
+                    // only affect one datagram.
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock
+                                | io::ErrorKind::TimedOut
+                                | io::ErrorKind::Interrupted
+                                | io::ErrorKind::InvalidData
+                                | io::ErrorKind::ConnectionRefused
+                        ) => {}
+                    Err(_) => break,
+                }
+
+                if let Some(idle) = self.options.idle_timeout {
+                    if last_sweep.elapsed() >= TICK {
+                        self.lock_peers().retain(|_, queue| queue.keep_alive(idle));
+                        last_sweep = Instant::now();
+                    }
+                }
+            }
+
+            let mut peers = self.lock_peers();
+            self.closed.store(true, Ordering::Release);
+            for (_, queue) in peers.drain() {
+                queue.close();
+            }
+        }
+    }
+
+    impl Queue {
+        fn new() -> Self {
+            Self {
+                state: Mutex::new(QueueState {
+                    datagrams: VecDeque::new(),
+                    last_active: Instant::now(),
+                    closed: false,
+                    handles: 0,
+                }),
+                ready: Condvar::new(),
+            }
+        }
+
+        fn lock(&self) -> MutexGuard<'_, QueueState> {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        fn push(&self, datagram: &[u8], max: usize) {
+            let mut state = self.lock();
+            state.last_active = Instant::now();
+            if state.closed || state.datagrams.len() >= max {
+                return;
+            }
+            state.datagrams.push_back(datagram.to_vec());
+            drop(state);
+            self.ready.notify_one();
+        }
+
+        fn touch(&self) -> io::Result<()> {
+            let mut state = self.lock();
+            if state.closed {
+                return Err(Error::MuxPeerClosed {}.into_io());
+            }
+            state.last_active = Instant::now();
+            drop(state);
+            Ok(())
+        }
+
+        // Closes the queue if it was idle for `idle`, returns whether it is open.
+        fn keep_alive(&self, idle: Duration) -> bool {
+            let mut state = self.lock();
+            if state.last_active.elapsed() < idle {
+                return true;
+            }
+            state.closed = true;
+            drop(state);
+            self.ready.notify_all();
+            false
+        }
+
+        fn close(&self) {
+            self.lock().closed = true;
+            self.ready.notify_all();
+        }
+    }
+
+    /// The datagrams of one remote address of a `UdpMux`.
+    #[derive(Debug)]
+    pub struct MuxPeer {
+        shared: Arc<Shared>,
+        addr: TargetAddr,
+        queue: Arc<Queue>,
+        read_timeout: Option<Duration>,
+    }
+
+    impl MuxPeer {
+        /// Sends a datagram to the peer.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::ConnectionAborted, socks2::Error::MuxPeerClosed)`
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.queue.touch()?;
+            self.shared.datagram.send_to(buf, &self.addr)
+        }
+
+        /// Receives the next queued datagram from the peer, truncated to `buf`.
+        ///
+        /// Queued datagrams are still returned after the peer is closed.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::ConnectionAborted, socks2::Error::MuxPeerClosed)`
+        /// - `std::io::ErrorKind::TimedOut`
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let deadline = self.read_timeout.map(|t| Instant::now() + t);
+            let mut state = self.queue.lock();
+            loop {
+                if let Some(datagram) = state.datagrams.pop_front() {
+                    drop(state);
+                    let len = cmp::min(datagram.len(), buf.len());
+                    buf[..len].copy_from_slice(&datagram[..len]);
+                    return Ok(len);
+                }
+                if state.closed {
+                    return Err(Error::MuxPeerClosed {}.into_io());
+                }
+
+                state = match deadline {
+                    None => self
+                        .queue
+                        .ready
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner),
+                    Some(deadline) => {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            return Err(io::ErrorKind::TimedOut.into());
+                        }
+                        self.queue
+                            .ready
+                            .wait_timeout(state, left)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                };
+            }
+        }
+
+        /// Sets the timeout for `recv`. `None` blocks until a datagram arrives or
+        /// the peer is closed, which is the default.
+        pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+            self.read_timeout = timeout;
+        }
+
+        /// Returns the address of the peer.
+        #[must_use]
+        pub const fn peer_addr(&self) -> &TargetAddr {
+            &self.addr
+        }
+    }
+
+    impl Drop for MuxPeer {
+        fn drop(&mut self) {
+            let mut peers = self.shared.lock_peers();
+            let mut state = self.queue.lock();
+            state.handles -= 1;
+            // An expired queue may have been replaced already.
+            let last = state.handles == 0
+                && peers
+                    .get(&self.addr)
+                    .map_or(false, |queue| Arc::ptr_eq(queue, &self.queue));
+            drop(state);
+            if last {
+                peers.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+pub mod probe {
+    use crate::{
+        resolve, tcp_stream_connect, unwrap_io_to_socks2_error,
+        v5::{client::Socks5Stream, Authentication},
+        ConnectOptions, Error, Resolver, Socks4Stream, TargetAddr, ToResolvedAddrs, ToTargetAddr,
+    };
+    use alloc::sync::Arc;
+    use core::time::Duration;
+    use std::{
+        io,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream},
+        time::Instant,
+    };
+
+    /// Options used by `probe_with_options`.
+    #[derive(Debug, Clone)]
+    pub struct ProbeOptions {
+        target: TargetAddr,
+        methods: Vec<u8>,
+        credentials: Option<(String, String)>,
+        timeout: Duration,
+        resolve: resolve::Settings,
+    }
+
+    impl Default for ProbeOptions {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ProbeOptions {
+        /// Creates the default options, which probe the auth methods "no
+        /// authentication", GSSAPI and "username/password", with `example.com:80`
+        /// as the target and a 5 second timeout.
+        #[must_use]
+        pub fn new() -> Self {
+            Self {
+                target: TargetAddr::Domain("example.com".to_owned(), 80),
+                methods: vec![0, 1, 2],
+                credentials: None,
+                timeout: Duration::from_secs(5),
+                resolve: resolve::Settings::new(),
+            }
+        }
+
+        /// Sets the target of the CONNECT, BIND, and SOCKS4A requests. SOCKS4A is
+        /// only probed for a domain.
+        ///
+        /// # Errors
+        /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+        pub fn target<T>(mut self, target: &T) -> io::Result<Self>
+        where
+            T: ToTargetAddr,
+        {
+            self.target = target.to_target_addr()?;
+            Ok(self)
+        }
+
+        /// Sets the SOCKS5 auth method IDs that are offered one at a time.
+        #[must_use]
+        pub fn methods(mut self, methods: &[u8]) -> Self {
+            methods.clone_into(&mut self.methods);
+            self
+        }
+
+        /// Authenticates the command requests with a username and password, if
+        /// the proxy accepts that method.
+        #[must_use]
+        pub fn password(mut self, username: &str, password: &str) -> Self {
+            self.credentials = Some((username.to_owned(), password.to_owned()));
+            self
+        }
+
+        /// Sets the connect, read, and write timeout of every connection made.
+        #[must_use]
+        pub const fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// Sets the `Resolver` for the host name of the proxy and the target
+        /// domain, like `ConnectOptions::resolver()`.
+        #[must_use]
+        pub fn resolver(mut self, resolver: Option<Arc<dyn Resolver>>) -> Self {
+            self.resolve.set_resolver(resolver);
+            self
+        }
+
+        /// Forbids resolving domain names locally, leaving SOCKS4A unknown if the
+        /// proxy rejects the target domain, like
+        /// `ConnectOptions::dns_leak_protection()`.
+        #[must_use]
+        pub fn dns_leak_protection(mut self, enabled: bool) -> Self {
+            self.resolve.set_leak_protection(enabled);
+            self
+        }
+    }
+
+    /// Whether a proxy allows a SOCKS5 command.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum CommandSupport {
+        /// The proxy granted the request.
+        Allowed,
+        /// The proxy replied "command not supported".
+        NotSupported,
+        /// The request failed for another reason, such as the ruleset, an
+        /// unreachable target, or the proxy closing the connection or not
+        /// replying in time.
+        Failed,
+    }
+
+    /// What a proxy supports, see `probe`.
+    #[derive(Debug, Clone, Default, Eq, PartialEq)]
+    pub struct ProbeReport {
+        socks4: Option<bool>,
+        socks4a: Option<bool>,
+        socks5: bool,
+        auth_methods: Vec<u8>,
+        connect: Option<CommandSupport>,
+        bind: Option<CommandSupport>,
+        udp_associate: Option<CommandSupport>,
+        latency: Option<Duration>,
+    }
+
+    impl ProbeReport {
+        /// Whether the proxy answered a SOCKS4 request, `None` if unknown.
+        ///
+        /// SOCKS4 has no IPv6, an IPv4-mapped target is sent as IPv4 and SOCKS4
+        /// is not probed for any other IPv6 target.
+        #[must_use]
+        pub const fn socks4(&self) -> Option<bool> {
+            self.socks4
+        }
+
+        /// Whether the proxy supports SOCKS4A, `None` if not probed or unknown.
+        ///
+        /// A rejected SOCKS4A request only counts as unsupported if a plain
+        /// SOCKS4 request to the IPv4 address of the target is granted.
+        #[must_use]
+        pub const fn socks4a(&self) -> Option<bool> {
+            self.socks4a
+        }
+
+        /// Whether the proxy answered a SOCKS5 greeting.
+        #[must_use]
+        pub const fn socks5(&self) -> bool {
+            self.socks5
+        }
+
+        /// The SOCKS5 auth method IDs the proxy accepted when offered alone.
+        #[must_use]
+        pub fn auth_methods(&self) -> &[u8] {
+            &self.auth_methods
+        }
+
+        /// Whether CONNECT is allowed, `None` if not probed.
+        #[must_use]
+        pub const fn connect(&self) -> Option<CommandSupport> {
+            self.connect
+        }
+
+        /// Whether BIND is allowed, `None` if not probed.
+        #[must_use]
+        pub const fn bind(&self) -> Option<CommandSupport> {
+            self.bind
+        }
+
+        /// Whether UDP ASSOCIATE is allowed, `None` if not probed.
+        #[must_use]
+        pub const fn udp_associate(&self) -> Option<CommandSupport> {
+            self.udp_associate
+        }
+
+        /// The time from connecting to the proxy until it answered the first
+        /// greeting, SOCKS5 or else SOCKS4.
+        #[must_use]
+        pub const fn latency(&self) -> Option<Duration> {
+            self.latency
+        }
+    }
+
+    /// Checks what a proxy supports with the default options.
+    ///
+    /// # Notes
+    /// See `probe_with_options()`.
+    ///
+    /// # Errors
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn probe<T>(proxy: T) -> io::Result<ProbeReport>
+    where
+        T: ToResolvedAddrs,
+    {
+        probe_with_options(proxy, &ProbeOptions::new())
+    }
+
+    /// Checks which SOCKS versions, SOCKS5 auth methods, and SOCKS5 commands a
+    /// proxy supports.
+    ///
+    /// # Notes
+    /// Every check uses its own connection. The commands are only probed if the
+    /// proxy accepted "no authentication", or "username/password" with
+    /// credentials set. A CONNECT to the target is made. If the proxy rejects
+    /// SOCKS4A, the target domain is resolved locally to compare with plain
+    /// SOCKS4. SOCKS4 is not probed for an IPv6 target, see
+    /// `ProbeReport::socks4()`.
+    ///
+    /// # Errors
+    /// - `std::io::ErrorKind::*` if the proxy cannot be reached
+    /// - `io::Error(std::io::ErrorKind::*, socks2::Error::*?)`
+    pub fn probe_with_options<T>(proxy: T, options: &ProbeOptions) -> io::Result<ProbeReport>
+    where
+        T: ToResolvedAddrs,
+    {
+        // Resolved once for all connections.
+        let proxy: Vec<SocketAddr> = proxy.to_resolved_addrs(options.resolve.resolver())?;
+        let mut report = ProbeReport::default();
+
+        // Only the first connection has to succeed, later checks fail on their own.
+        let start = Instant::now();
+        if Socks5Stream::greet(&mut connect(&proxy, options)?, &[0]).is_ok() {
+            report.latency = Some(start.elapsed());
+            report.socks5 = true;
+        }
+
+        if report.socks5 {
+            for &method in &options.methods {
+                let selected = connect(&proxy, options)
+                    .and_then(|mut socket| Socks5Stream::greet(&mut socket, &[method]));
+                if matches!(selected, Ok(selected) if selected == method) {
+                    report.auth_methods.push(method);
+                }
+            }
+
+            let auth = match options.credentials {
+                Some((ref username, ref password)) if report.auth_methods.contains(&2) => {
+                    Some(Authentication::Password { username, password })
+                }
+                _ if report.auth_methods.contains(&0) => Some(Authentication::None),
+                _ => None,
+            };
+            if let Some(auth) = auth {
+                // the proxy chooses the address for UDP ASSOCIATE
+                let unspecified =
+                    TargetAddr::Ip(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into());
+                report.connect = Some(command(&proxy, options, 1, &options.target, &auth));
+                report.bind = Some(command(&proxy, options, 2, &options.target, &auth));
+                report.udp_associate = Some(command(&proxy, options, 3, &unspecified, &auth));
+            }
+        }
+
+        let socks4_target = match options.target {
+            TargetAddr::Ip(SocketAddr::V6(addr)) => addr
+                .ip()
+                .to_ipv4_mapped()
+                .map(|ip| TargetAddr::Ip(SocketAddr::from((ip, addr.port())))),
+            ref target => Some(target.clone()),
+        };
+        let Some(socks4_target) = socks4_target else {
+            return Ok(report);
+        };
+
+        let start = Instant::now();
+        let granted = socks4(&proxy, options, &socks4_target);
+        report.socks4 = Some(granted.is_some());
+        if let Some(granted) = granted {
+            report.latency.get_or_insert_with(|| start.elapsed());
+            if let TargetAddr::Domain(ref domain, port) = options.target {
+                report.socks4a = if granted {
+                    Some(true)
+                } else {
+                    // Rejecting the domain only means no SOCKS4A if its IP is fine.
+                    options
+                        .resolve
+                        .resolve(domain, port)
+                        .ok()
+                        .and_then(|addrs| addrs.into_iter().find(SocketAddr::is_ipv4))
+                        .and_then(|addr| socks4(&proxy, options, &TargetAddr::Ip(addr)))
+                        .and_then(|granted| granted.then_some(false))
+                };
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn connect(proxy: &[SocketAddr], options: &ProbeOptions) -> io::Result<TcpStream> {
+        let socket =
+            tcp_stream_connect(proxy, &ConnectOptions::new().timeout(Some(options.timeout)))?;
+        socket.set_read_timeout(Some(options.timeout))?;
+        socket.set_write_timeout(Some(options.timeout))?;
+        Ok(socket)
+    }
+
+    fn command(
+        proxy: &[SocketAddr],
+        options: &ProbeOptions,
+        command: u8,
+        target: &TargetAddr,
+        auth: &Authentication,
+    ) -> CommandSupport {
+        let result = connect(proxy, options)
+            .and_then(|socket| Socks5Stream::handshake(socket, command, target, auth));
+        match result {
+            Ok(_) => CommandSupport::Allowed,
+            Err(err) => match unwrap_io_to_socks2_error(&err) {
+                Some(Error::ServerCmdNotSupported {}) => CommandSupport::NotSupported,
+                _ => CommandSupport::Failed,
+            },
+        }
+    }
+
+    // Sends a SOCKS4 CONNECT, SOCKS4A for a domain, returning whether it was
+    // granted, or `None` if the proxy did not answer like a SOCKS4 proxy.
+    fn socks4(proxy: &[SocketAddr], options: &ProbeOptions, target: &TargetAddr) -> Option<bool> {
+        let result = connect(proxy, options)
+            .and_then(|socket| Socks4Stream::handshake(socket, 1, target, ""));
+        match result {
+            Ok(_) => Some(true),
+            Err(err) => match unwrap_io_to_socks2_error(&err) {
+                None | Some(Error::InvalidResponseVersion { .. }) => None,
+                Some(_) => Some(false),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    #[cfg(feature = "bind")]
+    use super::bind::*;
+    #[cfg(feature = "client")]
+    use super::client::*;
+    #[cfg(feature = "udp")]
+    use super::{mux::*, udp::*};
+
+    use super::*;
+    use crate::{mock, unwrap_io_to_socks2_error};
+    use core::time::Duration;
+    use std::{
+        io::{IoSlice, Read, Write},
+        net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+        sync::mpsc,
+        thread,
+    };
+
+    const SOCKS_PROXY_NO_AUTH_ONLY: &str = "127.0.0.1:1084";
+    const SOCKS_PROXY_PASSWD_ONLY: &str = "127.0.0.1:1085";
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn google_no_auth() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let socket = Socks5Stream::connect(
+            SOCKS_PROXY_NO_AUTH_ONLY,
+            &addr,
+            Some(Duration::from_secs(25)),
+        )
+        .unwrap();
+        google(socket);
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn google_with_password() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let socket = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            &addr,
+            "testuser",
+            "testpass",
+            None,
+        )
+        .unwrap();
+        google(socket);
+    }
+
+    #[cfg(feature = "client")]
+    fn google(mut socket: Socks5Stream) {
+        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+
+        println!("{}", String::from_utf8_lossy(&result));
+        assert!(result.starts_with(b"HTTP/1.0"));
+        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn google_dns() {
+        let mut socket =
+            Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, &"google.com:80", None).unwrap();
+
+        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+
+        println!("{}", String::from_utf8_lossy(&result));
+        assert!(result.starts_with(b"HTTP/1.0"));
+        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn bind_no_auth() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind(
+            SOCKS_PROXY_NO_AUTH_ONLY,
+            &addr,
+            Some(Duration::from_secs(25)),
+        )
+        .unwrap();
+        bind(listener);
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn bind_with_password_supported_but_no_auth_used() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind_with_password(
+            SOCKS_PROXY_NO_AUTH_ONLY,
+            &addr,
+            "unused_and_invalid_username",
+            "unused_and_invalid_password",
+            None,
+        )
+        .unwrap();
+        bind(listener);
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn bind_with_password() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind_with_password(
+            "127.0.0.1:1085",
+            &addr,
+            "testuser",
+            "testpass",
+            None,
+        )
+        .unwrap();
+        bind(listener);
+    }
+
+    #[cfg(feature = "bind")]
+    fn bind(listener: Socks5Listener) {
+        let addr = listener.proxy_addr().clone();
+        let mut end = TcpStream::connect(addr).unwrap();
+        let mut conn = listener.accept().unwrap();
+        conn.write_all(b"hello world").unwrap();
+        drop(conn);
+        let mut result = vec![];
+        end.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"hello world");
+    }
+
+    // First figure out our local address that we'll be connecting from
+    #[cfg(feature = "client")]
+    fn find_address() -> TargetAddr {
+        let socket =
+            Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, &"google.com:80", None).unwrap();
+        socket.proxy_addr().to_owned()
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_no_auth() {
+        let socks =
+            Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15410", None).unwrap();
+        associate(&socks, "127.0.0.1:15411");
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_with_password() {
+        let socks = Socks5Datagram::bind_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            "127.0.0.1:15414",
+            "testuser",
+            "testpass",
+            None,
+        )
+        .unwrap();
+        associate(&socks, "127.0.0.1:15415");
+    }
+
+    #[cfg(feature = "udp")]
+    fn associate(socks: &Socks5Datagram, socket_addr: &str) {
+        let socket = UdpSocket::bind(socket_addr).unwrap();
+
+        socks.send_to(b"hello world!", &socket_addr).unwrap();
+        let mut buf = [0; 13];
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(&buf[..12], b"hello world!");
+
+        socket.send_to(b"hello world!", addr).unwrap();
+
+        let len = socks.recv_from(&mut buf).unwrap().0;
+        assert_eq!(len, 12);
+        assert_eq!(&buf[..12], b"hello world!");
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    #[allow(clippy::cast_possible_truncation)]
+    fn associate_long() {
+        let socks =
+            Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15412", None).unwrap();
+        let socket_addr = "127.0.0.1:15413";
+        let socket = UdpSocket::bind(socket_addr).unwrap();
+
+        let mut msg = vec![];
+        for i in 0..(MAX_ADDR_LEN + 100) {
+            msg.push(i as u8);
+        }
+
+        socks.send_to(&msg, &socket_addr).unwrap();
+        let mut buf = vec![0; msg.len() + 1];
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(len, msg.len());
+        assert_eq!(msg, &buf[..msg.len()]);
+
+        socket.send_to(&msg, addr).unwrap();
+
+        let mut buf = vec![0; msg.len() + 1];
+        let len = socks.recv_from(&mut buf).unwrap().0;
+        assert_eq!(len, msg.len());
+        assert_eq!(msg, &buf[..msg.len()]);
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn reply_does_not_over_read() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            // reply with a domain address followed by application data, in one write
+            let mut reply = vec![5, 0, 0, 3, 9];
+            reply.extend(b"localhost");
+            reply.extend(8080_u16.to_be_bytes());
+            reply.extend(b"hello");
+            stream.write_all(&reply).unwrap();
+        });
+
+        let mut socket = Socks5Stream::connect(proxy, &"example.com:80", None).unwrap();
+        assert_eq!(
+            socket.proxy_addr(),
+            &TargetAddr::Domain("localhost".to_owned(), 8080)
+        );
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"hello");
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn short_failure_reply() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            stream.write_all(&[5, 2]).unwrap();
+        });
+
+        let err = Socks5Stream::connect(proxy, &"example.com:80", None).unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::ServerRefusedByRuleSet {})
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn pipelined() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            // everything must arrive before the server says anything
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(mock::read_greeting(&mut stream), [0]);
+            assert_eq!(mock::read_request(&mut stream).0, 1);
+            let mut early = [0; 5];
+            stream.read_exact(&mut early).unwrap();
+            assert_eq!(&early, b"hello");
+
+            stream.write_all(&[5, 0]).unwrap();
+            mock::reply(&mut stream, 0, "127.0.0.1:80".parse().unwrap());
+            stream.write_all(b"world").unwrap();
+        });
+
+        let mut socket =
+            Socks5Stream::connect_pipelined(proxy, &"example.com:80", b"hello", None).unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"world");
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn pipelined_password_other_method() {
+        let (proxy, _) = mock::spawn(2, |mut stream| {
+            match mock::read_greeting(&mut stream)[..] {
+                // pipelined attempt, this server only does no auth
+                [2] => stream.write_all(&[5, 0xff]).unwrap(),
+                [2, 0] => {
+                    stream.write_all(&[5, 0]).unwrap();
+                    assert_eq!(mock::read_request(&mut stream).0, 1);
+                    mock::reply(&mut stream, 0, "127.0.0.1:80".parse().unwrap());
+                    let mut early = [0; 5];
+                    stream.read_exact(&mut early).unwrap();
+                    stream.write_all(&early).unwrap();
+                }
+                ref methods => panic!("unexpected methods {methods:?}"),
+            }
+        });
+
+        let mut socket = Socks5Stream::connect_with_password_pipelined(
+            proxy,
+            &"example.com:80",
+            "testuser",
+            "testpass",
+            b"hello",
+            None,
+        )
+        .unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"hello");
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn stream_socket_api() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:80".parse().unwrap());
+            let mut result = vec![];
+            stream.read_to_end(&mut result).unwrap();
+            stream.write_all(&result).unwrap();
+        });
+
+        let socket = Socks5Stream::connect(proxy, &"example.com:80", None).unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), proxy);
+        assert_eq!(
+            socket.local_addr().unwrap(),
+            socket.get_ref().local_addr().unwrap()
+        );
+        socket.set_nodelay(true).unwrap();
+        assert!(socket.nodelay().unwrap());
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_secs(5)));
+
+        let mut clone = socket.try_clone().unwrap();
+        assert_eq!(clone.proxy_addr(), socket.proxy_addr());
+        let bufs = [IoSlice::new(b"hello "), IoSlice::new(b"world")];
+        let written = clone.write_vectored(&bufs).unwrap();
+        clone.write_all(&b"hello world"[written..]).unwrap();
+        socket.shutdown(Shutdown::Write).unwrap();
+
+        let mut result = vec![];
+        TcpStream::from(socket).read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"hello world");
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_loop() {
+        for ahead in [false, true] {
+            let mut conn = 0;
+            let (proxy, _) = mock::spawn(if ahead { 4 } else { 3 }, move |mut stream| {
+                mock::greet(&mut stream);
+                assert_eq!(mock::read_request(&mut stream).0, 2);
+                // the port is reused for the second BIND
+                let port = if conn < 2 { 4000 } else { 4001 };
+                mock::reply(&mut stream, 0, SocketAddr::from(([127, 0, 0, 1], port)));
+                if ahead && conn == 1 {
+                    // the BIND ahead reports the address being accepted and is dropped
+                    conn += 1;
+                    return;
+                }
+                mock::reply(&mut stream, 0, "127.0.0.1:5000".parse().unwrap());
+                stream.write_all(&[conn]).unwrap();
+                conn += 1;
+            });
+
+            let mut accept_loop = Socks5AcceptLoop::new(proxy, &"127.0.0.1:5000", None).unwrap();
+            accept_loop.set_bind_ahead(ahead);
+            let mut advertised = vec![];
+            let conns: Vec<u8> = accept_loop
+                .incoming(|addr| {
+                    advertised.push(addr.clone());
+                    Ok(())
+                })
+                .take(3)
+                .map(|stream| {
+                    let mut buf = [0];
+                    stream.unwrap().read_exact(&mut buf).unwrap();
+                    buf[0]
+                })
+                .collect();
+
+            assert_eq!(conns, if ahead { [0, 2, 3] } else { [0, 1, 2] });
+            assert_eq!(
+                advertised,
+                [
+                    TargetAddr::Ip("127.0.0.1:4000".parse().unwrap()),
+                    TargetAddr::Ip("127.0.0.1:4001".parse().unwrap())
+                ]
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn bind_unspecified_addr() {
+        let mut conn = 0;
+        let (proxy, _) = mock::spawn(3, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            match conn {
+                0 => mock::reply(&mut stream, 0, "0.0.0.0:4000".parse().unwrap()),
+                1 => mock::reply(&mut stream, 0, "[::]:4001".parse().unwrap()),
+                _ => {
+                    let mut reply = vec![5, 0, 0, 3, 9];
+                    reply.extend(b"localhost");
+                    reply.extend(4002_u16.to_be_bytes());
+                    stream.write_all(&reply).unwrap();
+                }
+            }
+            conn += 1;
+            mock::drain(&mut stream);
+        });
+
+        for port in [4000, 4001] {
+            let listener = Socks5Listener::bind(proxy, &"127.0.0.1:5000", None).unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            assert_eq!(listener.proxy_addr(), &TargetAddr::Ip(addr));
+            assert_eq!(listener.advertised_socket_addr().unwrap(), addr);
+        }
+
+        let listener = Socks5Listener::bind(proxy, &"127.0.0.1:5000", None).unwrap();
+        assert_eq!(
+            listener.proxy_addr(),
+            &TargetAddr::Domain("localhost".to_owned(), 4002)
+        );
+        let addr = listener.advertised_socket_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 4002);
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_from() {
+        let (proxy, _) = mock::spawn(2, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:4000".parse().unwrap());
+            mock::reply(&mut stream, 0, "192.0.2.7:5000".parse().unwrap());
+            mock::drain(&mut stream);
+        });
+
+        let expected = "192.0.2.0/24".parse().unwrap();
+        let listener = Socks5Listener::bind(proxy, &"0.0.0.0:0", None).unwrap();
+        listener.accept_from(&expected).unwrap();
+
+        let expected = "198.51.100.7".parse().unwrap();
+        let listener = Socks5Listener::bind(proxy, &"0.0.0.0:0", None).unwrap();
+        let err = listener.accept_from(&expected).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(
+            unwrap_io_to_socks2_error(&err),
+            Some(Error::UnexpectedBindPeer { peer })
+                if peer == &TargetAddr::Ip("192.0.2.7:5000".parse().unwrap())
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_timeout_deadline() {
+        use std::time::Instant;
+
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:4000".parse().unwrap());
+            // every read gets a byte well within the timeout
+            for byte in [5, 0, 0, 1, 127, 0, 0, 1, 0x13, 0x88] {
+                thread::sleep(Duration::from_millis(40));
+                stream.write_all(&[byte]).unwrap();
+            }
+            mock::drain(&mut stream);
+        });
+
+        let mut listener = Socks5Listener::bind(proxy, &"127.0.0.1:5000", None).unwrap();
+        let start = Instant::now();
+        let err = listener
+            .accept_timeout(Duration::from_millis(200))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(350));
+
+        let stream = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            stream.proxy_addr(),
+            &TargetAddr::Ip("127.0.0.1:5000".parse().unwrap())
+        );
+        assert_eq!(stream.get_ref().read_timeout().unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_timeout() {
+        let (tx, rx) = mpsc::channel::<()>();
+        let (proxy, _) = mock::spawn(1, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:4000".parse().unwrap());
+            // the second reply is split across the timeouts
+            rx.recv().unwrap();
+            stream.write_all(&[5, 0, 0, 1, 127]).unwrap();
+            rx.recv().unwrap();
+            stream.write_all(&[0, 0, 1, 0x13, 0x88]).unwrap();
+            mock::drain(&mut stream);
+        });
+
+        let mut listener = Socks5Listener::bind(proxy, &"127.0.0.1:5000", None).unwrap();
+        let err = listener
+            .accept_timeout(Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            listener.try_accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        tx.send(()).unwrap();
+        let err = listener
+            .accept_timeout(Duration::from_millis(200))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        tx.send(()).unwrap();
+        let stream = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            stream.proxy_addr(),
+            &TargetAddr::Ip("127.0.0.1:5000".parse().unwrap())
+        );
+        // the timeout only applied while waiting
+        assert_eq!(stream.get_ref().read_timeout().unwrap(), None);
+        assert_eq!(
+            unwrap_io_to_socks2_error(&listener.try_accept().unwrap_err()),
+            Some(&Error::ListenerAccepted {})
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "bind")]
+    fn accept_abort() {
+        let (proxy, _) = mock::spawn(1, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:4000".parse().unwrap());
+            mock::drain(&mut stream);
+        });
+
+        let listener = Socks5Listener::bind(proxy, &"127.0.0.1:5000", None).unwrap();
+        let handle = listener.abort_handle().unwrap();
+        let waiter = std::thread::spawn(move || listener.accept());
+        std::thread::sleep(Duration::from_millis(50));
+        handle.abort().unwrap();
+
+        let err = waiter.join().unwrap().unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::BindAborted {})
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn resolver() {
+        use crate::ConnectOptions;
+
+        let (proxy, _) = mock::spawn(4, |mut stream| {
+            mock::greet(&mut stream);
+            let (_, addr) = mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, mock::decode_addr(&addr));
+            mock::drain(&mut stream);
+        });
+        let hosts = mock::Hosts::new(&[
+            ("proxy.resolver.test", proxy),
+            ("proxy-str.resolver.test", proxy),
+            ("accept-loop.resolver.test", proxy),
+        ]);
+        let options = ConnectOptions::new().resolver(Some(hosts.caching()));
+        let target: SocketAddr = "192.0.2.9:80".parse().unwrap();
+
+        // the proxy is looked up once, then cached
+        let proxy_domain = TargetAddr::Domain("proxy.resolver.test".to_owned(), proxy.port());
+        for _ in 0..2 {
+            let stream =
+                Socks5Stream::connect_with_options(&proxy_domain, &target, &options).unwrap();
+            assert_eq!(stream.proxy_addr(), &TargetAddr::Ip(target));
+        }
+        assert_eq!(hosts.lookups("proxy.resolver.test"), 1);
+
+        // a host name given as a string goes through the resolver as well
+        let proxy_str = format!("proxy-str.resolver.test:{}", proxy.port());
+        for _ in 0..2 {
+            Socks5Stream::connect_with_options(proxy_str.as_str(), &target, &options).unwrap();
+        }
+        assert_eq!(hosts.lookups("proxy-str.resolver.test"), 1);
+
+        #[cfg(feature = "bind")]
+        {
+            let proxy = ("accept-loop.resolver.test", proxy.port());
+            Socks5AcceptLoop::with_options(proxy, &target, &options).unwrap();
+            assert_eq!(hosts.lookups("accept-loop.resolver.test"), 1);
+        }
+
+        // the resolver is not used by other connections
+        assert!(Socks5Stream::connect(&proxy_domain, &target, None).is_err());
+        assert_eq!(hosts.lookups("proxy.resolver.test"), 1);
     }
 
     #[test]
-    #[cfg(feature = "bind")]
-    fn bind_no_auth() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind(
-            SOCKS_PROXY_NO_AUTH_ONLY,
-            &addr,
-            Some(Duration::from_secs(25)),
-        )
-        .unwrap();
-        bind(listener);
+    #[cfg(feature = "client")]
+    fn probe() {
+        // accepts "no authentication" and "username/password", drops BIND,
+        // refuses UDP ASSOCIATE, and grants SOCKS4A for one domain only
+        let (proxy, _) = mock::spawn(32, |mut stream| {
+            let mut version = [0];
+            stream.read_exact(&mut version).unwrap();
+            if version[0] == 4 {
+                let mut request = [0; 7];
+                stream.read_exact(&mut request).unwrap();
+                while stream.read_be_u8().unwrap() != 0 {}
+                let mut code = 90;
+                if request[3..6] == [0, 0, 0] {
+                    let mut domain = vec![];
+                    loop {
+                        match stream.read_be_u8().unwrap() {
+                            0 => break,
+                            byte => domain.push(byte),
+                        }
+                    }
+                    if domain != b"granted.probe.test" {
+                        code = 91;
+                    }
+                }
+                stream.write_all(&[0, code, 0, 80, 192, 0, 2, 1]).unwrap();
+                return;
+            }
+            let mut methods = vec![0; usize::from(stream.read_be_u8().unwrap())];
+            stream.read_exact(&mut methods).unwrap();
+            let method = methods[0];
+            let selected = if method == 0 || method == 2 {
+                method
+            } else {
+                0xff
+            };
+            stream.write_all(&[5, selected]).unwrap();
+            let mut head = [0; 3];
+            if method != 0 || stream.read_exact(&mut head).is_err() {
+                return;
+            }
+            mock::read_addr(&mut stream);
+            match head[1] {
+                2 => {}
+                3 => mock::reply(&mut stream, 7, "192.0.2.1:80".parse().unwrap()),
+                _ => mock::reply(&mut stream, 0, "192.0.2.1:80".parse().unwrap()),
+            }
+        });
+
+        let options = probe::ProbeOptions::new()
+            .target(&"granted.probe.test:80")
+            .unwrap();
+        let report = probe::probe_with_options(proxy, &options).unwrap();
+        assert!(report.socks5());
+        assert_eq!(report.auth_methods(), [0, 2]);
+        assert_eq!(report.connect(), Some(probe::CommandSupport::Allowed));
+        assert_eq!(report.bind(), Some(probe::CommandSupport::Failed));
+        assert_eq!(
+            report.udp_associate(),
+            Some(probe::CommandSupport::NotSupported)
+        );
+        assert_eq!(report.socks4(), Some(true));
+        assert_eq!(report.socks4a(), Some(true));
+        assert!(report.latency().is_some());
+
+        // rejected as a domain, but granted as an IP
+        let hosts = mock::Hosts::new(&[("rejected.probe.test", "192.0.2.1:0".parse().unwrap())]);
+        let options = probe::ProbeOptions::new()
+            .target(&"rejected.probe.test:80")
+            .unwrap()
+            .resolver(Some(hosts.caching()));
+        let report = probe::probe_with_options(proxy, &options).unwrap();
+        assert_eq!(report.socks4(), Some(true));
+        assert_eq!(report.socks4a(), Some(false));
+
+        // SOCKS4 cannot carry an IPv6 target, unless it is IPv4-mapped
+        let options = probe::ProbeOptions::new()
+            .target(&"[2001:db8::1]:80")
+            .unwrap();
+        let report = probe::probe_with_options(proxy, &options).unwrap();
+        assert_eq!(report.connect(), Some(probe::CommandSupport::Allowed));
+        assert_eq!(report.socks4(), None);
+        assert_eq!(report.socks4a(), None);
+
+        let options = probe::ProbeOptions::new()
+            .target(&"[::ffff:192.0.2.1]:80")
+            .unwrap();
+        let report = probe::probe_with_options(proxy, &options).unwrap();
+        assert_eq!(report.socks4(), Some(true));
+        assert_eq!(report.socks4a(), None);
     }
 
     #[test]
-    #[cfg(feature = "bind")]
-    fn bind_with_password_supported_but_no_auth_used() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind_with_password(
-            SOCKS_PROXY_NO_AUTH_ONLY,
-            &addr,
-            "unused_and_invalid_username",
-            "unused_and_invalid_password",
-            None,
-        )
-        .unwrap();
-        bind(listener);
+    #[cfg(all(target_os = "linux", feature = "fast-open"))]
+    fn fast_open() {
+        use crate::ConnectOptions;
+        use core::{mem, ptr};
+        use std::{fs, net::TcpListener, os::unix::io::AsRawFd};
+
+        // Not in libc, set when the SYN carried data that the server accepted.
+        const TCPI_OPT_SYN_DATA: u8 = 32;
+
+        #[allow(clippy::cast_possible_truncation)]
+        fn getsockopt<T>(socket: &TcpStream, name: libc::c_int) -> T {
+            // SAFETY: Only used with plain old data.
+            let mut value: T = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<T>() as libc::socklen_t;
+            // SAFETY: value and len are valid for writes of their sizes.
+            let r = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    name,
+                    ptr::addr_of_mut!(value).cast(),
+                    &mut len,
+                )
+            };
+            assert_eq!(r, 0);
+            value
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let qlen: libc::c_int = 8;
+        #[allow(clippy::cast_possible_truncation)]
+        let len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: qlen lives for the duration of the call and the length matches it.
+        let r = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                ptr::addr_of!(qlen).cast(),
+                len,
+            )
+        };
+        assert_eq!(r, 0);
+        let (proxy, _) = mock::serve(listener, 2, |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, "127.0.0.1:80".parse().unwrap());
+            mock::drain(&mut stream);
+        });
+
+        // Without both the client (1) and server (2) bits the kernel falls back
+        // to a normal handshake.
+        let sysctl = fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen")
+            .map_or(0, |sysctl| sysctl.trim().parse::<i32>().unwrap_or(0));
+        let enabled = sysctl & 3 == 3;
+
+        let options = ConnectOptions::new()
+            .timeout(Some(Duration::from_secs(5)))
+            .fast_open(true);
+        for i in 0..2 {
+            let socket =
+                Socks5Stream::connect_with_options(proxy, &"example.com:80", &options).unwrap();
+            assert_eq!(
+                socket.proxy_addr(),
+                &TargetAddr::Ip("127.0.0.1:80".parse().unwrap())
+            );
+            let socket = socket.get_ref();
+            assert_eq!(
+                getsockopt::<libc::c_int>(socket, libc::TCP_FASTOPEN_CONNECT),
+                1
+            );
+            // the first connect only gets a cookie, the second sends the greeting
+            // in the SYN
+            let info = getsockopt::<libc::tcp_info>(socket, libc::TCP_INFO);
+            assert_eq!(
+                info.tcpi_options & TCPI_OPT_SYN_DATA != 0,
+                enabled && i == 1
+            );
+        }
+    }
+
+    // Associates through a mock proxy whose relay is the returned socket.
+    #[cfg(feature = "udp")]
+    fn mock_associate() -> (Socks5Datagram, UdpSocket) {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let proxy = mock_associate_proxy(relay_addr);
+        let socks = Socks5Datagram::bind(proxy, "127.0.0.1:0", None).unwrap();
+        (socks, relay)
+    }
+
+    #[cfg(feature = "udp")]
+    fn mock_associate_proxy(reply: SocketAddr) -> SocketAddr {
+        mock::spawn(1, move |mut stream| {
+            mock::greet(&mut stream);
+            assert_eq!(mock::read_request(&mut stream).0, 3);
+            mock::reply(&mut stream, 0, reply);
+            mock::drain(&mut stream);
+        })
+        .0
     }
 
     #[test]
-    #[cfg(feature = "bind")]
-    fn bind_with_password() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind_with_password(
-            "127.0.0.1:1085",
-            &addr,
-            "testuser",
-            "testpass",
-            None,
-        )
-        .unwrap();
-        bind(listener);
+    #[cfg(feature = "udp")]
+    fn associate_unspecified_relay_unconnected() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        let proxy = mock_associate_proxy(SocketAddr::from(([0, 0, 0, 0], relay_port)));
+        let options = DatagramOptions::new().unconnected(true);
+        let socks =
+            Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+        assert_eq!(socks.relay_addrs(), &[relay.local_addr().unwrap()]);
+
+        let to = "127.0.0.1:53".parse().unwrap();
+        socks.send_to(b"ping", &to).unwrap();
+        let mut buf = [0; 32];
+        let (len, local) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], mock::udp_packet(0, to, b"ping"));
+
+        // datagrams that do not come from the relay are dropped
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger
+            .send_to(&mock::udp_packet(0, to, b"spoofed"), local)
+            .unwrap();
+        relay
+            .send_to(&mock::udp_packet(0, to, b"pong"), local)
+            .unwrap();
+        // peeking leaves them queued
+        for _ in 0..2 {
+            let err = socks.peek_from(&mut buf).unwrap_err();
+            assert_eq!(
+                unwrap_io_to_socks2_error(&err),
+                Some(&Error::DatagramNotFromRelay {
+                    addr: stranger.local_addr().unwrap()
+                })
+            );
+        }
+        let (len, addr) = socks.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(addr, TargetAddr::Ip(to));
     }
 
-    #[cfg(feature = "bind")]
-    fn bind(listener: Socks5Listener) {
-        let addr = listener.proxy_addr().clone();
-        let mut end = TcpStream::connect(addr).unwrap();
-        let mut conn = listener.accept().unwrap();
-        conn.write_all(b"hello world").unwrap();
-        drop(conn);
-        let mut result = vec![];
-        end.read_to_end(&mut result).unwrap();
-        assert_eq!(result, b"hello world");
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_advertise_source() {
+        for bind_addr in ["127.0.0.1:0", "0.0.0.0:0"] {
+            let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let relay_addr = relay.local_addr().unwrap();
+            let (tx, rx) = mpsc::channel();
+            let (proxy, _) = mock::spawn(1, move |mut stream| {
+                mock::greet(&mut stream);
+                let (cmd, addr) = mock::read_request(&mut stream);
+                assert_eq!(cmd, 3);
+                tx.send(mock::decode_addr(&addr)).unwrap();
+                mock::reply(&mut stream, 0, relay_addr);
+                mock::drain(&mut stream);
+            });
+
+            let options = DatagramOptions::new().advertise_source(true);
+            let socks =
+                Socks5Datagram::bind_with_options(proxy, bind_addr, &options, None).unwrap();
+            let port = socks.get_ref().local_addr().unwrap().port();
+            assert_eq!(rx.recv().unwrap(), SocketAddr::from(([127, 0, 0, 1], port)));
+        }
     }
 
-    // First figure out our local address that we'll be connecting from
-    #[cfg(feature = "client")]
-    fn find_address() -> TargetAddr {
-        let socket =
-            Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, &"google.com:80", None).unwrap();
-        socket.proxy_addr().to_owned()
+    // Serves one UDP over TCP session, checking the request for `version` and
+    // answering each packet read with `pong` from the same address.
+    #[cfg(feature = "udp")]
+    fn mock_udp_over_tcp(version: UdpOverTcp, packets: Vec<(SocketAddr, Vec<u8>)>) -> SocketAddr {
+        mock::spawn(1, move |mut stream| {
+            mock::greet(&mut stream);
+            let (cmd, addr) = mock::read_request(&mut stream);
+            assert_eq!(cmd, 1);
+            let magic = match version {
+                UdpOverTcp::V1 => &b"sp.udp-over-tcp.arpa"[..],
+                UdpOverTcp::V2 => b"sp.v2.udp-over-tcp.arpa",
+            };
+            assert_eq!(addr[0], 3);
+            assert_eq!(&addr[2..addr.len() - 2], magic);
+            mock::reply(&mut stream, 0, SocketAddr::from(([0, 0, 0, 0], 0)));
+
+            if version == UdpOverTcp::V2 {
+                // not a connect request, to an unspecified destination
+                assert_eq!(stream.read_be_u8().unwrap(), 0);
+                assert_eq!(mock::read_addr(&mut stream), [1, 0, 0, 0, 0, 0, 0]);
+            }
+            for (to, payload) in &packets {
+                let packet = mock::uot_packet(*to, payload);
+                let mut read = vec![0; packet.len()];
+                stream.read_exact(&mut read).unwrap();
+                assert_eq!(read, packet);
+                stream.write_all(&mock::uot_packet(*to, b"pong")).unwrap();
+            }
+            mock::drain(&mut stream);
+        })
+        .0
     }
 
     #[test]
     #[cfg(feature = "udp")]
-    fn associate_no_auth() {
+    fn associate_udp_over_tcp() {
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let to6: SocketAddr = "[::1]:53".parse().unwrap();
+        let packets = vec![(to, b"ping".to_vec()), (to6, vec![7; 300])];
+        for version in [UdpOverTcp::V1, UdpOverTcp::V2] {
+            let proxy = mock_udp_over_tcp(version, packets.clone());
+            let options = DatagramOptions::new().udp_over_tcp(Some(version));
+            let mut socks =
+                Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+            assert!(socks.relay_addrs().is_empty());
+            // datagrams are never fragmented
+            socks.set_fragment_size(Some(100));
+
+            let mut buf = [0; 32];
+            for (to, payload) in &packets {
+                socks.send_to(payload, to).unwrap();
+                let (len, addr) = socks.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..len], b"pong");
+                assert_eq!(addr, TargetAddr::Ip(*to));
+            }
+        }
+
+        let proxy = mock_udp_over_tcp(UdpOverTcp::V2, vec![]);
+        let options = DatagramOptions::new().udp_over_tcp(Some(UdpOverTcp::V2));
         let socks =
-            Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15410", None).unwrap();
-        associate(&socks, "127.0.0.1:15411");
+            Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+        let err = socks.send_to(&vec![0; 0x10000], &to).unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::DatagramTooLarge { size: 0 })
+        );
+
+        // rejected before connecting to the proxy
+        for (options, second) in [
+            (options.clone().unconnected(true), "unconnected"),
+            (options.advertise_source(true), "advertise_source"),
+        ] {
+            let err =
+                Socks5Datagram::bind_with_options("127.0.0.1:9", "127.0.0.1:0", &options, None)
+                    .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(
+                unwrap_io_to_socks2_error(&err),
+                Some(&Error::IncompatibleOptions {
+                    first: "udp_over_tcp",
+                    second
+                })
+            );
+        }
     }
 
     #[test]
     #[cfg(feature = "udp")]
-    fn associate_with_password() {
-        let socks = Socks5Datagram::bind_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            "127.0.0.1:15414",
-            "testuser",
-            "testpass",
-            None,
-        )
-        .unwrap();
-        associate(&socks, "127.0.0.1:15415");
+    fn associate_many() {
+        let (socks, relay) = mock_associate();
+        let local = socks.get_ref().local_addr().unwrap();
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let to6: SocketAddr = "[::1]:53".parse().unwrap();
+
+        let datagrams: [(&[u8], SocketAddr); 3] = [(b"one", to), (b"two", to6), (b"three", to)];
+        assert_eq!(socks.send_many(&datagrams).unwrap(), 3);
+        let mut buf = [0; 64];
+        for (payload, addr) in datagrams {
+            let len = relay.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], mock::udp_packet(0, addr, payload));
+        }
+
+        relay
+            .send_to(&mock::udp_packet(0, to, b"one"), local)
+            .unwrap();
+        relay
+            .send_to(&mock::udp_packet(1, to, b"dropped"), local)
+            .unwrap();
+        relay
+            .send_to(&mock::udp_packet(0, to6, b"three"), local)
+            .unwrap();
+        let mut received = vec![];
+        while received.len() < 2 {
+            let mut bufs = [[0; 32]; 4];
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+            let datagrams = socks.recv_many(&mut slices).unwrap();
+            for (i, (len, addr)) in datagrams.into_iter().enumerate() {
+                received.push((bufs[i][..len].to_vec(), addr));
+            }
+        }
+        assert_eq!(
+            received,
+            [
+                (b"one".to_vec(), TargetAddr::Ip(to)),
+                (b"three".to_vec(), TargetAddr::Ip(to6))
+            ]
+        );
+
+        // the buffers of the last call are reused for a smaller batch
+        relay
+            .send_to(&mock::udp_packet(0, to, b"four"), local)
+            .unwrap();
+        let mut buf = [0; 32];
+        let datagrams = socks.recv_many(&mut [&mut buf[..]]).unwrap();
+        assert_eq!(datagrams, [(4, TargetAddr::Ip(to))]);
+        assert_eq!(&buf[..4], b"four");
     }
 
+    #[test]
     #[cfg(feature = "udp")]
-    fn associate(socks: &Socks5Datagram, socket_addr: &str) {
-        let socket = UdpSocket::bind(socket_addr).unwrap();
+    fn associate_view() {
+        let (socks, relay) = mock_associate();
+        let local = socks.get_ref().local_addr().unwrap();
+        let from: SocketAddr = "127.0.0.1:53".parse().unwrap();
 
-        socks.send_to(b"hello world!", &socket_addr).unwrap();
-        let mut buf = [0; 13];
-        let (len, addr) = socket.recv_from(&mut buf).unwrap();
-        assert_eq!(len, 12);
-        assert_eq!(&buf[..12], b"hello world!");
+        relay
+            .send_to(&mock::udp_packet(0, from, b"hello"), local)
+            .unwrap();
+        let mut buf = [0; 64];
+        let (addr, payload) = socks.recv_from_view(&mut buf).unwrap();
+        assert_eq!(payload, b"hello");
+        assert_eq!(addr, TargetAddr::Ip(from));
 
-        socket.send_to(b"hello world!", addr).unwrap();
+        // larger than both the header buffer and `buf`
+        let msg: Vec<u8> = (0..=255).cycle().take(MAX_ADDR_LEN + 100).collect();
+        relay
+            .send_to(&mock::udp_packet(0, from, &msg), local)
+            .unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = socks.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[..len], msg[..64]);
+    }
 
-        let len = socks.recv_from(&mut buf).unwrap().0;
-        assert_eq!(len, 12);
-        assert_eq!(&buf[..12], b"hello world!");
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_headers() {
+        let (socks, relay) = mock_associate();
+        let local = socks.get_ref().local_addr().unwrap();
+
+        // headers shorter than, as long as, and longer than the IPv4 one
+        let mut headers = vec![];
+        for addr in ["127.0.0.1:53", "[::1]:53"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            headers.push((mock::udp_packet(0, addr, &[]), TargetAddr::Ip(addr)));
+        }
+        for len in [1_u8, 2, 255] {
+            let domain = "a".repeat(len.into());
+            let mut header = vec![0, 0, 0, 3, len];
+            header.extend(domain.as_bytes());
+            header.extend(53_u16.to_be_bytes());
+            headers.push((header, TargetAddr::Domain(domain, 53)));
+        }
+
+        // payloads that fit, fill `buf` exactly, and overflow it
+        let msg: Vec<u8> = (0..=255).cycle().take(300).collect();
+        for (header, addr) in &headers {
+            for size in [5, 64, 300] {
+                let mut packet = header.clone();
+                packet.extend(&msg[..size]);
+                relay.send_to(&packet, local).unwrap();
+
+                let mut buf = [0; 64];
+                let (len, from) = socks.peek_from(&mut buf).unwrap();
+                assert_eq!((&buf[..len], &from), (&msg[..size.min(64)], addr));
+                let mut buf = [0; 64];
+                let (len, from) = socks.recv_from(&mut buf).unwrap();
+                assert_eq!((&buf[..len], &from), (&msg[..size.min(64)], addr));
+            }
+        }
     }
 
     #[test]
     #[cfg(feature = "udp")]
-    #[allow(clippy::cast_possible_truncation)]
-    fn associate_long() {
+    fn associate_connect() {
+        let (mut socks, relay) = mock_associate();
+        let local = socks.local_addr().unwrap();
+        let target: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        assert_eq!(
+            unwrap_io_to_socks2_error(&socks.send(b"ping").unwrap_err()),
+            Some(&Error::DatagramNotConnected {})
+        );
+        socks.connect(&target).unwrap();
+        assert_eq!(socks.peer_addr(), Some(&TargetAddr::Ip(target)));
+
+        socks.send(b"ping").unwrap();
+        let mut buf = [0; 32];
+        let (len, _) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], mock::udp_packet(0, target, b"ping"));
+
+        // datagrams from other remote addresses are dropped
+        let other = "127.0.0.1:54".parse().unwrap();
+        relay
+            .send_to(&mock::udp_packet(0, other, b"other"), local)
+            .unwrap();
+        relay
+            .send_to(&mock::udp_packet(0, target, b"pong"), local)
+            .unwrap();
+        let len = socks.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_mux() {
+        let (socks, relay) = mock_associate();
+        let local = socks.local_addr().unwrap();
+        let dns: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let quic: SocketAddr = "127.0.0.1:443".parse().unwrap();
+
+        let mux = UdpMux::new(socks).unwrap();
+        let mut dns_peer = mux.peer(&dns).unwrap();
+        let mut quic_peer = mux.peer(&quic).unwrap();
+        dns_peer.set_read_timeout(Some(Duration::from_secs(5)));
+        quic_peer.set_read_timeout(Some(Duration::from_secs(5)));
+
+        quic_peer.send(b"initial").unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], mock::udp_packet(0, quic, b"initial"));
+
+        for (addr, payload) in [
+            (quic, &b"handshake"[..]),
+            ("127.0.0.1:1".parse().unwrap(), b"unknown"),
+            (dns, b"answer"),
+        ] {
+            relay
+                .send_to(&mock::udp_packet(0, addr, payload), local)
+                .unwrap();
+        }
+        let len = dns_peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"answer");
+        let len = quic_peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"handshake");
+
+        drop(dns_peer);
+        assert_eq!(mux.peer_count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_mux_over_tcp() {
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let (proxy, _) = mock::spawn(1, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, SocketAddr::from(([0, 0, 0, 0], 0)));
+            stream.read_exact(&mut [0; 8]).unwrap();
+
+            let mut frames = vec![];
+            for payload in [&b"split"[..], b"whole"] {
+                frames.extend(mock::uot_packet(to, payload));
+            }
+            // the first frame is cut across several ticks of the mux
+            stream.write_all(&frames[..5]).unwrap();
+            thread::sleep(Duration::from_millis(350));
+            stream.write_all(&frames[5..]).unwrap();
+            mock::drain(&mut stream);
+        });
+
+        let options = DatagramOptions::new().udp_over_tcp(Some(UdpOverTcp::V2));
         let socks =
-            Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15412", None).unwrap();
-        let socket_addr = "127.0.0.1:15413";
-        let socket = UdpSocket::bind(socket_addr).unwrap();
+            Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+        let mux = UdpMux::new(socks).unwrap();
+        let mut peer = mux.peer(&to).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5)));
 
-        let mut msg = vec![];
-        for i in 0..(MAX_ADDR_LEN + 100) {
-            msg.push(i as u8);
+        let mut buf = [0; 64];
+        for payload in [&b"split"[..], b"whole"] {
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], payload);
         }
+    }
 
-        socks.send_to(&msg, &socket_addr).unwrap();
-        let mut buf = vec![0; msg.len() + 1];
-        let (len, addr) = socket.recv_from(&mut buf).unwrap();
-        assert_eq!(len, msg.len());
-        assert_eq!(msg, &buf[..msg.len()]);
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_mux_idle() {
+        let (socks, _relay) = mock_associate();
+        let options = MuxOptions::new().idle_timeout(Some(Duration::from_millis(200)));
+        let mux = UdpMux::with_options(socks, &options).unwrap();
+        let peer = mux.peer(&"127.0.0.1:53").unwrap();
 
-        socket.send_to(&msg, addr).unwrap();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&peer.recv(&mut [0; 8]).unwrap_err()),
+            Some(&Error::MuxPeerClosed {})
+        );
+        assert_eq!(mux.peer_count(), 0);
+    }
 
-        let mut buf = vec![0; msg.len() + 1];
-        let len = socks.recv_from(&mut buf).unwrap().0;
-        assert_eq!(len, msg.len());
-        assert_eq!(msg, &buf[..msg.len()]);
+    // Waits for the watcher to notice that the control connection closed.
+    #[cfg(feature = "udp")]
+    fn wait_lost(socks: &Socks5Datagram) {
+        for _ in 0..500 {
+            if socks.is_lost() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("control connection loss was not detected");
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_control_lost() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let (proxy, _) = mock::spawn(2, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, relay_addr);
+        });
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        let socks = Socks5Datagram::bind(proxy, "127.0.0.1:0", None).unwrap();
+        wait_lost(&socks);
+        assert_eq!(
+            unwrap_io_to_socks2_error(&socks.send_to(b"ping", &to).unwrap_err()),
+            Some(&Error::ControlConnectionLost {})
+        );
+
+        let options = DatagramOptions::new().udp_over_tcp(Some(UdpOverTcp::V1));
+        let socks =
+            Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&socks.recv_from(&mut [0; 8]).unwrap_err()),
+            Some(&Error::ControlConnectionLost {})
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_reassociate() {
+        let relays = [
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        ];
+        let relay_addrs = [
+            relays[0].local_addr().unwrap(),
+            relays[1].local_addr().unwrap(),
+        ];
+        let mut conns = relay_addrs.into_iter();
+        let (proxy, _) = mock::spawn(2, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            let relay = conns.next().unwrap();
+            mock::reply(&mut stream, 0, relay);
+            // the first association ends right away
+            if relay == relay_addrs[1] {
+                mock::drain(&mut stream);
+            }
+        });
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        let options = DatagramOptions::new().reassociate(true);
+        let socks =
+            Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+        assert_eq!(socks.relay_addrs(), [relay_addrs[0]]);
+        wait_lost(&socks);
+
+        socks.send_to(b"ping", &to).unwrap();
+        assert_eq!(socks.current_relay_addrs(), [relay_addrs[1]]);
+        assert_eq!(socks.relay_addrs(), [relay_addrs[0]]);
+        assert!(!socks.is_lost());
+        let mut buf = [0; 32];
+        let (len, _) = relays[1].recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], mock::udp_packet(0, to, b"ping"));
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_control_lost_while_receiving() {
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        for (unconnected, reassociate) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let relay_addr = relay.local_addr().unwrap();
+            let (tx, rx) = mpsc::channel();
+            let mut first = true;
+            let (proxy, _) = mock::spawn(2, move |mut stream| {
+                mock::greet(&mut stream);
+                mock::read_request(&mut stream);
+                mock::reply(&mut stream, 0, relay_addr);
+                if first {
+                    // closed while the receive below is blocked
+                    first = false;
+                    thread::sleep(Duration::from_millis(200));
+                } else {
+                    // after the socket is connected to the relay again
+                    thread::sleep(Duration::from_millis(100));
+                    let local = rx.recv().unwrap();
+                    relay
+                        .send_to(&mock::udp_packet(0, to, b"pong"), local)
+                        .unwrap();
+                    mock::drain(&mut stream);
+                }
+            });
+
+            let options = DatagramOptions::new()
+                .unconnected(unconnected)
+                .reassociate(reassociate);
+            let socks =
+                Socks5Datagram::bind_with_options(proxy, "127.0.0.1:0", &options, None).unwrap();
+            socks
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            tx.send(socks.local_addr().unwrap()).unwrap();
+
+            let mut buf = [0; 32];
+            if reassociate {
+                let (len, addr) = socks.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..len], b"pong");
+                assert_eq!(addr, TargetAddr::Ip(to));
+            } else {
+                // woken up by the loss instead of timing out
+                let err = socks.recv_from(&mut buf).unwrap_err();
+                assert_eq!(
+                    unwrap_io_to_socks2_error(&err),
+                    Some(&Error::ControlConnectionLost {})
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_split() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let (closed_tx, closed_rx) = mpsc::channel();
+        let (proxy, _) = mock::spawn(1, move |mut stream| {
+            mock::greet(&mut stream);
+            mock::read_request(&mut stream);
+            mock::reply(&mut stream, 0, relay_addr);
+            mock::drain(&mut stream);
+            closed_tx.send(()).unwrap();
+        });
+        let socks = Socks5Datagram::bind(proxy, "127.0.0.1:0", None).unwrap();
+        let local = socks.local_addr().unwrap();
+        let to: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        let (sender, receiver) = socks.split().unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        std::thread::spawn(move || sender.send_to(b"ping", &to).unwrap())
+            .join()
+            .unwrap();
+        // the association outlives the sender
+        assert!(closed_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let mut buf = [0; 32];
+        let (len, _) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], mock::udp_packet(0, to, b"ping"));
+
+        relay
+            .send_to(&mock::udp_packet(0, to, b"pong"), local)
+            .unwrap();
+        let (len, addr) = receiver.peek_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], &addr), (&b"pong"[..], &TargetAddr::Ip(to)));
+        let (len, addr) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], addr), (&b"pong"[..], TargetAddr::Ip(to)));
+
+        drop(receiver);
+        closed_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_reassembly() {
+        let (mut socks, relay) = mock_associate();
+        let local = socks.get_ref().local_addr().unwrap();
+        let from = "127.0.0.1:53".parse().unwrap();
+
+        relay
+            .send_to(&mock::udp_packet(1, from, b"hel"), local)
+            .unwrap();
+        let err = socks.recv_from(&mut [0; 16]).unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::InvalidFragmentID { fid: 1 })
+        );
+
+        socks.set_reassembly_timeout(Some(Duration::from_secs(1)));
+        for packet in [
+            mock::udp_packet(1, from, b"xx"),
+            // lower position abandons the queue
+            mock::udp_packet(1, from, b"hel"),
+            mock::udp_packet(2, from, b"lo "),
+            mock::udp_packet(0x83, from, b"world"),
+        ] {
+            relay.send_to(&packet, local).unwrap();
+        }
+        let mut buf = [0; 16];
+        let (len, addr) = socks.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello world");
+        assert_eq!(addr, TargetAddr::Ip(from));
+
+        relay
+            .send_to(&mock::udp_packet(0, from, b"standalone"), local)
+            .unwrap();
+        let (len, _) = socks.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"standalone");
+    }
+
+    #[test]
+    #[cfg(feature = "udp")]
+    fn associate_fragmentation() {
+        let (mut socks, relay) = mock_associate();
+        let to = "127.0.0.1:53".parse().unwrap();
+
+        socks.set_fragment_size(Some(4));
+        socks.send_to(b"hello world", &to).unwrap();
+        let mut buf = [0; 32];
+        for (frag, payload) in [(1, &b"hell"[..]), (2, b"o wo"), (0x83, b"rld")] {
+            let len = relay.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], mock::udp_packet(frag, to, payload));
+        }
+
+        let err = socks.send_to(&[0; 4 * 128], &to).unwrap_err();
+        assert_eq!(
+            unwrap_io_to_socks2_error(&err),
+            Some(&Error::TooManyFragments { count: 128 })
+        );
     }
 
     #[test]
@@ -0,0 +1,97 @@
+//! Checks that DNS leak protection makes no local lookups.
+//!
+//! Leak protection is process wide, so this runs in its own test binary.
+
+#![allow(clippy::unwrap_used)]
+
+use socks2::{
+    dns_leak_protection, enable_dns_leak_protection, set_resolver, unwrap_io_to_socks2_error,
+    Error, Lookup, Socks4Stream, Socks5Stream, TargetAddr,
+};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+// Reads until and without the next NUL byte.
+fn read_str(stream: &mut TcpStream) -> Vec<u8> {
+    let mut out = vec![];
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == 0 {
+            return out;
+        }
+        out.push(byte[0]);
+    }
+}
+
+// A proxy for one SOCKS4 and one SOCKS5 CONNECT that checks the target was
+// sent as a domain.
+fn mock_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 8];
+        stream.read_exact(&mut request).unwrap();
+        // SOCKS4A marks the domain with the IP 0.0.0.x
+        assert_eq!(request[..2], [4, 1]);
+        assert_eq!(request[4..7], [0, 0, 0]);
+        read_str(&mut stream);
+        assert_eq!(read_str(&mut stream), b"target.leak.test");
+        stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        stream.write_all(&[5, 0]).unwrap();
+        let mut request = [0; 5];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 3]);
+        let mut domain = vec![0; usize::from(request[4]) + 2];
+        stream.read_exact(&mut domain).unwrap();
+        assert_eq!(domain[..domain.len() - 2], *b"target.leak.test");
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+    });
+    addr
+}
+
+fn assert_refused(err: &io::Error) {
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(matches!(
+        unwrap_io_to_socks2_error(err),
+        Some(Error::LocalDnsRefused { domain }) if domain == "target.leak.test"
+    ));
+}
+
+#[test]
+fn no_local_lookups() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    set_resolver(Some(Arc::new(move |_: &str, port| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(Lookup::new(vec![SocketAddr::from(([192, 0, 2, 1], port))]))
+    })));
+    enable_dns_leak_protection();
+
+    let target = TargetAddr::Domain("target.leak.test".to_owned(), 80);
+    assert_refused(&target.to_socket_addrs().err().unwrap());
+    assert_refused(&TcpStream::connect(&target).unwrap_err());
+
+    let proxy = mock_proxy();
+    Socks4Stream::connect(proxy, &target, "", None).unwrap();
+    Socks5Stream::connect(proxy, &target, None).unwrap();
+
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+    // it stays enabled for the rest of the process
+    assert!(dns_leak_protection());
+    assert_refused(&target.to_socket_addrs().err().unwrap());
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+}